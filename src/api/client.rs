@@ -0,0 +1,35 @@
+use super::ApiResponse;
+use serde::de::DeserializeOwned;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// Send a GET request for `path` (including the query string) to the API server at `addr`,
+/// and parse the JSON response body.
+pub fn get<T: DeserializeOwned>(addr: &str, path: &str) -> Result<T, String> {
+    let mut stream = TcpStream::connect(addr).map_err(|e| format!("error connecting to {}: {}", addr, e))?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr)
+        .map_err(|e| format!("error sending request to {}: {}", addr, e))?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)
+        .map_err(|e| format!("error reading response from {}: {}", addr, e))?;
+    let body = match response.find("\r\n\r\n") {
+        Some(i) => &response[i + 4..],
+        None => return Err(format!("malformed response from {}", addr)),
+    };
+    serde_json::from_str(body).map_err(|e| {
+        match serde_json::from_str::<ApiResponse>(body) {
+            Ok(ApiResponse { success: false, message }) => message,
+            _ => format!("error parsing response from {}: {}", addr, e),
+        }
+    })
+}
+
+/// Send a GET request whose response is a plain `ApiResponse`, and return its message on success.
+pub fn call(addr: &str, path: &str) -> Result<String, String> {
+    let response: ApiResponse = get(addr, path)?;
+    if response.success {
+        Ok(response.message)
+    } else {
+        Err(response.message)
+    }
+}
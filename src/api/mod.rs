@@ -1,10 +1,23 @@
-use serde::Serialize;
+pub mod client;
+
+use serde::{Serialize, Deserialize};
 use crate::miner::Handle as MinerHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::blockchain::Blockchain;
+use crate::mempool::{Mempool, MempoolStats, RejectReason};
+use crate::payment_channel::ChannelManager;
+use crate::persistence::Persistence;
+use crate::work::{parse_header, Solution, WorkManager};
+use crate::crypto::hash::H256;
+use crate::address::H160;
+use crate::transaction::{SignedTransaction, TransactionInput, TransactionOutput};
+use crate::verifier::Handle as VerifierHandle;
 
 use log::info;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
 use tiny_http::Response;
@@ -15,14 +28,41 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    verifier: VerifierHandle,
+    channels: Arc<Mutex<ChannelManager>>,
+    work: Arc<Mutex<WorkManager>>,
+    persistence: Option<Persistence>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 struct ApiResponse {
     success: bool,
     message: String,
 }
 
+/// The tip of the longest chain
+#[derive(Serialize, Deserialize)]
+pub struct TipResponse {
+    pub hash: H256,
+    pub height: u64,
+}
+
+/// The contents of the mempool and what happened to the transactions submitted to it
+#[derive(Serialize, Deserialize)]
+pub struct MempoolResponse {
+    pub transactions: usize,
+    pub bytes: usize,
+    pub stats: MempoolStats,
+}
+
+/// Unspent outputs of an address at the tip of the longest chain
+#[derive(Serialize, Deserialize)]
+pub struct UtxoResponse {
+    pub utxos: Vec<(TransactionInput, TransactionOutput)>,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -36,22 +76,98 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+/// Get a required query parameter, or respond with an error and return from the handler
+macro_rules! require_param {
+    ( $req:expr, $params:expr, $name:expr ) => {{
+        match $params.get($name) {
+            Some(v) => v,
+            None => {
+                respond_result!($req, false, format!("missing {}", $name));
+                return;
+            }
+        }
+    }};
+}
+
+/// Get a required query parameter and parse it with `$parse`, or respond with an error and return from the handler
+macro_rules! parse_param {
+    ( $req:expr, $params:expr, $name:expr, $parse:expr ) => {{
+        let value = require_param!($req, $params, $name);
+        match $parse(value) {
+            Ok(v) => v,
+            Err(e) => {
+                respond_result!($req, false, format!("error parsing {}: {}", $name, e));
+                return;
+            }
+        }
+    }};
+}
+
+/// Parse a hex string of exactly `N` bytes
+fn parse_hex<T: From<[u8; N]>, const N: usize>(s: &str) -> Result<T, String> {
+    let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+    let array: [u8; N] = bytes[..].try_into().map_err(|_| format!("expected {} bytes", N))?;
+    Ok(array.into())
+}
+
+/// Parse a hex-encoded, bincode-serialized transaction
+fn parse_tx(s: &str) -> Result<SignedTransaction, String> {
+    let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+    bincode::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
+fn parse_u64(s: &str) -> Result<u64, String> {
+    s.parse::<u64>().map_err(|e| e.to_string())
+}
+
+fn parse_f64(s: &str) -> Result<f64, String> {
+    s.parse::<f64>().map_err(|e| e.to_string())
+}
+
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        verifier: &VerifierHandle,
+        channels: &Arc<Mutex<ChannelManager>>,
+        work: &Arc<Mutex<WorkManager>>,
+        persistence: &Option<Persistence>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            verifier: verifier.clone(),
+            channels: Arc::clone(channels),
+            work: Arc::clone(work),
+            persistence: persistence.clone(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
+                let blockchain = Arc::clone(&server.blockchain);
+                let mempool = Arc::clone(&server.mempool);
+                let verifier = server.verifier.clone();
+                let channels = Arc::clone(&server.channels);
+                let work = Arc::clone(&server.work);
+                let persistence = server.persistence.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -87,14 +203,228 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/simulate" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let rate = parse_param!(req, params, "rate", parse_f64);
+                            if !(rate > 0.0 && rate.is_finite()) {
+                                respond_result!(req, false, format!("rate {} is not positive", rate));
+                                return;
+                            }
+                            if !blockchain.lock().unwrap().is_regtest() {
+                                respond_result!(req, false, "simulated mining needs a regtest chain");
+                                return;
+                            }
+                            miner.simulate(rate);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/pause" => {
+                            match miner.pause() {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/resume" => {
+                            match miner.resume() {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/config" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let payout_address: Option<H160> = match params.get("address").map(|v| parse_hex(v)) {
+                                Some(Ok(address)) => Some(address),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let threads = match params.get("threads").map(|v| parse_u64(v)) {
+                                Some(Ok(0)) => {
+                                    respond_result!(req, false, "the miner needs at least one thread");
+                                    return;
+                                }
+                                Some(Ok(threads)) => Some(threads as usize),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing threads: {}", e));
+                                    return;
+                                }
+                                None => None,
+                            };
+                            match miner.config(payout_address, threads) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/status" => {
+                            match miner.status() {
+                                Some(status) => respond_json!(req, status),
+                                None => respond_result!(req, false, "miner has shut down"),
+                            }
+                        }
                         "/miner/exit" => {
                             miner.exit();
                             respond_result!(req, true, "ok");
                         }
+                        "/node/shutdown" => {
+                            if let Some(persistence) = &persistence {
+                                persistence.save();
+                            }
+                            respond_result!(req, true, "ok");
+                            info!("Shutting down");
+                            std::process::exit(0);
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/blockchain/tip" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let hash = blockchain.tip();
+                            let height = blockchain.get_height(&hash);
+                            respond_json!(req, TipResponse { hash, height });
+                        }
+                        "/blockchain/utxos" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address: H160 = parse_param!(req, params, "address", parse_hex);
+                            let blockchain = blockchain.lock().unwrap();
+                            let utxos = blockchain.get_state(&blockchain.tip()).iter()
+                                .filter(|(_, output)| output.recipient == address)
+                                .map(|(input, output)| (*input, output.clone()))
+                                .collect();
+                            respond_json!(req, UtxoResponse { utxos });
+                        }
+                        "/transaction/submit" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let tx = parse_param!(req, params, "tx", parse_tx);
+                            // verify the signature before taking the locks:
+                            let signers = verifier.verify_one(&tx);
+                            let blockchain = blockchain.lock().unwrap();
+                            let result = match signers {
+                                Some(signers) => mempool.lock().unwrap().accept_verified(tx, &signers, &blockchain),
+                                None => Err(RejectReason::Invalid),
+                            };
+                            match result {
+                                Ok(hash) => {
+                                    network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                                    respond_result!(req, true, hash);
+                                }
+                                Err(reason) => respond_result!(req, false, reason),
+                            }
+                        }
+                        "/mining/getwork" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let worker = require_param!(req, params, "worker");
+                            let result = work.lock().unwrap().get_work(worker);
+                            match result {
+                                Ok(work) => respond_json!(req, work),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/mining/submitwork" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let worker = require_param!(req, params, "worker");
+                            let id = parse_param!(req, params, "id", parse_u64);
+                            let header = parse_param!(req, params, "header", parse_header);
+                            let result = work.lock().unwrap().submit_work(worker, id, header);
+                            match result {
+                                Ok(Solution::Share) => respond_result!(req, true, "share"),
+                                Ok(Solution::Block(hash)) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/pool/stats" => {
+                            let stats = work.lock().unwrap().pool().map(|pool| pool.stats());
+                            match stats {
+                                Some(stats) => respond_json!(req, stats),
+                                None => respond_result!(req, false, "not running a pool"),
+                            }
+                        }
+                        "/fee/estimate" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let target = parse_param!(req, params, "target", parse_u64);
+                            match mempool.lock().unwrap().fee_estimator().estimate(target) {
+                                Some(fee_rate) => respond_result!(req, true, fee_rate),
+                                None => respond_result!(req, false, "not enough data"),
+                            }
+                        }
+                        "/mempool/stats" => {
+                            let mempool = mempool.lock().unwrap();
+                            respond_json!(req, MempoolResponse {
+                                transactions: mempool.len(),
+                                bytes: mempool.total_size(),
+                                stats: mempool.stats().clone(),
+                            });
+                        }
+                        "/mempool/evicted" => {
+                            let evictions: Vec<_> = mempool.lock().unwrap().evictions().cloned().collect();
+                            respond_json!(req, evictions);
+                        }
+                        "/htlc/preimage" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let hash_lock: H256 = parse_param!(req, params, "hash", parse_hex);
+                            match blockchain.lock().unwrap().find_preimage(&hash_lock) {
+                                Some(preimage) => respond_result!(req, true, hex::encode(preimage)),
+                                None => respond_result!(req, false, "preimage not revealed"),
+                            }
+                        }
+                        "/transaction/payload" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let payload_hash: H256 = parse_param!(req, params, "hash", parse_hex);
+                            let records = blockchain.lock().unwrap().find_payload(&payload_hash);
+                            respond_json!(req, records);
+                        }
+                        "/channel/list" => {
+                            let mut channels = channels.lock().unwrap();
+                            channels.update();
+                            let channels: Vec<_> = channels.channels().iter()
+                                .map(|(id, channel)| (*id, channel.clone()))
+                                .collect();
+                            respond_json!(req, channels);
+                        }
+                        "/channel/open" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let payee: H160 = parse_param!(req, params, "payee", parse_hex);
+                            let capacity = parse_param!(req, params, "capacity", parse_u64);
+                            let timeout = parse_param!(req, params, "timeout", parse_u64);
+                            match channels.lock().unwrap().open(payee, capacity, timeout) {
+                                Ok(id) => respond_result!(req, true, id),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/channel/pay" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let id: H256 = parse_param!(req, params, "id", parse_hex);
+                            let amount = parse_param!(req, params, "amount", parse_u64);
+                            match channels.lock().unwrap().pay(&id, amount) {
+                                Ok(commitment) => respond_result!(req, true, hex::encode(bincode::serialize(&commitment).unwrap())),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/channel/receive" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let commitment = parse_param!(req, params, "tx", parse_tx);
+                            match channels.lock().unwrap().receive(commitment) {
+                                Ok(paid) => respond_result!(req, true, paid),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/channel/close" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let id: H256 = parse_param!(req, params, "id", parse_hex);
+                            match channels.lock().unwrap().close(&id) {
+                                Ok(hash) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/channel/refund" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let id: H256 = parse_param!(req, params, "id", parse_hex);
+                            match channels.lock().unwrap().refund(&id) {
+                                Ok(hash) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -116,3 +446,68 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, SystemClock};
+    use crate::crypto::key_pair;
+    use crate::miner::{self, MinerStatus};
+    use crate::network::server;
+    use crate::verifier;
+    use crossbeam::channel::unbounded;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::net::TcpListener;
+
+    /// Start an API server on a free port, with a paused single-thread miner paying `[1u8; 20]`
+    fn start_server() -> (server::Context, String) {
+        let (msg_sender, _msg_receiver) = unbounded();
+        let (server_ctx, network) = server::new("127.0.0.1:0".parse().unwrap(), msg_sender).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let (miner_ctx, miner) = miner::new(&network, &blockchain, &mempool, 1, H160::from([1u8; 20]), StdRng::seed_from_u64(0), &clock);
+        miner_ctx.start();
+        let (_verifier_ctx, verifier) = verifier::new(1);
+        let channels = Arc::new(Mutex::new(ChannelManager::new(
+            &network,
+            &blockchain,
+            &mempool,
+            key_pair::random(),
+        )));
+        let work = Arc::new(Mutex::new(WorkManager::new(&network, &blockchain, &mempool, None, H160::from([1u8; 20]), &clock)));
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        Server::start(addr, &miner, &network, &blockchain, &mempool, &verifier, &channels, &work, &None);
+        (server_ctx, addr.to_string())
+    }
+
+    #[test]
+    fn miner_endpoints() {
+        let (_server_ctx, addr) = start_server();
+        let status = || client::get::<MinerStatus>(&addr, "/miner/status").unwrap();
+        assert_eq!(status().state, "paused");
+        assert_eq!(status().threads, 1);
+        assert_eq!(status().payout_address, H160::from([1u8; 20]));
+
+        // pausing and resuming keep the lambda:
+        assert!(client::call(&addr, "/miner/resume").is_ok());
+        assert_eq!(status().state, "paused");
+        client::call(&addr, "/miner/start?lambda=1000000").unwrap();
+        assert_eq!((status().state.as_str(), status().lambda), ("running", Some(1_000_000)));
+        client::call(&addr, "/miner/pause").unwrap();
+        assert_eq!((status().state.as_str(), status().lambda), ("paused", None));
+        client::call(&addr, "/miner/resume").unwrap();
+        assert_eq!((status().state.as_str(), status().lambda), ("running", Some(1_000_000)));
+
+        // the configuration is checked, then applied:
+        let address = hex::encode([2u8; 20]);
+        assert_eq!(client::call(&addr, "/miner/config?threads=0"), Err("the miner needs at least one thread".to_string()));
+        assert!(client::call(&addr, "/miner/config?address=zz").is_err());
+        client::call(&addr, &format!("/miner/config?address={}&threads=3", address)).unwrap();
+        let status = status();
+        assert_eq!(status.threads, 3);
+        assert_eq!(status.payout_address, H160::from([2u8; 20]));
+        assert_eq!(status.hash_rates.len(), 3);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::address::H160;
+use crate::api::client;
+use crate::api::{TipResponse, UtxoResponse};
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::{hash_preimage, Script, SignedTransaction, Transaction, TransactionInput, TransactionOutput, Witness};
+
+use log::info;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::thread;
+use std::time;
+
+/// How often to poll the nodes while waiting for the other party or for confirmations
+const POLL_INTERVAL_MILLISECONDS: u64 = 1000;
+/// The fee of each transaction of the swap, taken from the swapped coins
+const FEE: u64 = 1_000;
+
+/// The parameters of a cross-chain atomic swap. Alice pays one of her coins on chain A to Bob,
+/// and Bob pays one of his coins on chain B to Alice.
+pub struct Swap {
+    /// API address of a node on chain A
+    pub api_a: String,
+    /// API address of a node on chain B
+    pub api_b: String,
+    pub alice: Ed25519KeyPair,
+    pub bob: Ed25519KeyPair,
+    /// Blocks until Alice can take back her coin on chain A
+    pub timeout_a: u64,
+    /// Blocks until Bob can take back his coin on chain B. Must be smaller than `timeout_a`,
+    /// so that Bob has time to claim on chain A after Alice reveals the secret on chain B.
+    pub timeout_b: u64,
+}
+
+/// A hash time-locked output that has been broadcast
+struct Lock {
+    outpoint: TransactionInput,
+    output: TransactionOutput,
+}
+
+fn address(key: &Ed25519KeyPair) -> H160 {
+    H160::from_pubkey(key.public_key().as_ref())
+}
+
+fn tip_height(api: &str) -> Result<u64, String> {
+    let tip: TipResponse = client::get(api, "/blockchain/tip")?;
+    Ok(tip.height)
+}
+
+fn utxos(api: &str, owner: &H160) -> Result<Vec<(TransactionInput, TransactionOutput)>, String> {
+    let response: UtxoResponse = client::get(api, &format!("/blockchain/utxos?address={}", owner))?;
+    Ok(response.utxos)
+}
+
+fn submit(api: &str, tx: &SignedTransaction) -> Result<(), String> {
+    let encoded = hex::encode(bincode::serialize(tx).unwrap());
+    client::call(api, &format!("/transaction/submit?tx={}", encoded))?;
+    Ok(())
+}
+
+fn sleep() {
+    thread::sleep(time::Duration::from_millis(POLL_INTERVAL_MILLISECONDS));
+}
+
+/// Build the transaction locking `coin` of `sender` in a hash time-locked output that `recipient`
+/// can claim with the preimage of `hash_lock` before height `timeout`
+fn lock_transaction(
+    coin: (TransactionInput, TransactionOutput),
+    sender: &Ed25519KeyPair,
+    recipient: H160,
+    hash_lock: H256,
+    timeout: u64,
+) -> Result<(SignedTransaction, Lock), String> {
+    let (input, spent) = coin;
+    let value = spent.value.checked_sub(FEE)
+        .ok_or_else(|| format!("coin worth {} cannot pay the fee {}", spent.value, FEE))?;
+    let output = TransactionOutput {
+        recipient,
+        value,
+        script: Script::HashTimeLock { hash_lock, refund: address(sender), timeout },
+    };
+    let raw = Transaction {
+        TransactionInput: vec![input],
+        TransactionOutput: vec![output.clone()],
+    };
+    let outpoint = TransactionInput { txid: 0, prev_tx: raw.hash() };
+    Ok((SignedTransaction::from_raw(raw, sender), Lock { outpoint, output }))
+}
+
+/// Lock one of `sender`'s coins in a hash time-locked output that `recipient` can claim with the
+/// preimage of `hash_lock` within `timeout` blocks
+fn lock(api: &str, sender: &Ed25519KeyPair, recipient: H160, hash_lock: H256, timeout: u64) -> Result<Lock, String> {
+    let coin = utxos(api, &address(sender))?.into_iter()
+        .find(|(_, output)| output.script == Script::PayToAddress)
+        .ok_or_else(|| format!("{} has no coin to lock on {}", address(sender), api))?;
+    let (tx, lock) = lock_transaction(coin, sender, recipient, hash_lock, tip_height(api)? + timeout)?;
+    submit(api, &tx)?;
+    Ok(lock)
+}
+
+/// Build the transaction spending a locked coin to `key`, by its claimer revealing `witness`
+/// or, after the timeout, by its sender
+fn spend_transaction(lock: &Lock, key: &Ed25519KeyPair, witness: Vec<Witness>) -> Result<SignedTransaction, String> {
+    let value = lock.output.value.checked_sub(FEE)
+        .ok_or_else(|| format!("locked coin worth {} cannot pay the fee {}", lock.output.value, FEE))?;
+    let raw = Transaction {
+        TransactionInput: vec![lock.outpoint],
+        TransactionOutput: vec![TransactionOutput {
+            recipient: address(key),
+            value,
+            script: Script::PayToAddress,
+        }],
+    };
+    Ok(SignedTransaction::from_raw_with_witness(raw, key, witness))
+}
+
+/// Spend a locked coin to its claimer (revealing `witness`) or, after the timeout, back to its sender.
+/// Returns the new output.
+fn spend(api: &str, lock: &Lock, key: &Ed25519KeyPair, witness: Vec<Witness>) -> Result<TransactionInput, String> {
+    let tx = spend_transaction(lock, key, witness)?;
+    let outpoint = TransactionInput { txid: 0, prev_tx: tx.raw.hash() };
+    submit(api, &tx)?;
+    Ok(outpoint)
+}
+
+fn timeout(lock: &Lock) -> u64 {
+    match lock.output.script {
+        Script::HashTimeLock { timeout, .. } => timeout,
+        _ => unreachable!(),
+    }
+}
+
+/// Wait until `outpoint`, owned by `owner`, is in the state of the longest chain,
+/// giving up once the chain reaches `deadline`
+fn wait_for_output(api: &str, owner: &H160, outpoint: &TransactionInput, deadline: u64) -> Result<(), String> {
+    loop {
+        if utxos(api, owner)?.iter().any(|(input, _)| input == outpoint) {
+            return Ok(());
+        }
+        if tip_height(api)? >= deadline {
+            return Err(format!("output {:?} not confirmed on {} before height {}", outpoint, api, deadline));
+        }
+        sleep();
+    }
+}
+
+/// Wait until `lock` has timed out on its chain, then take the coin back
+fn refund(api: &str, lock: &Lock, sender: &Ed25519KeyPair) -> Result<(), String> {
+    // the refund is valid in the first block at height `timeout`, i.e. on top of height `timeout - 1`
+    while tip_height(api)? + 1 < timeout(lock) {
+        sleep();
+    }
+    info!("Refunding {:?} on {}", lock.outpoint, api);
+    spend(api, lock, sender, vec![]).map(|_| ())
+}
+
+impl Swap {
+    /// Run the swap protocol to completion:
+    /// 1. Alice picks a secret and locks her coin on chain A to Bob under the secret's hash.
+    /// 2. Once that confirms, Bob locks his coin on chain B to Alice under the same hash, with a shorter timeout.
+    /// 3. Once that confirms, Alice claims Bob's coin on chain B, revealing the secret.
+    /// 4. Bob reads the secret from chain B and claims Alice's coin on chain A.
+    ///
+    /// If the counterparty stops cooperating, the party that locked a coin refunds it after its timeout.
+    pub fn run(&self) -> Result<(), String> {
+        if self.timeout_b >= self.timeout_a {
+            return Err("timeout on chain B must be shorter than on chain A".to_string());
+        }
+        let secret: [u8; 32] = rand::random();
+        let hash_lock = hash_preimage(&secret);
+        let alice = address(&self.alice);
+        let bob = address(&self.bob);
+
+        info!("Alice locks a coin on {} for Bob under hash {}", self.api_a, hash_lock);
+        let lock_a = lock(&self.api_a, &self.alice, bob, hash_lock, self.timeout_a)?;
+        wait_for_output(&self.api_a, &bob, &lock_a.outpoint, timeout(&lock_a))?;
+
+        info!("Bob locks a coin on {} for Alice under the same hash", self.api_b);
+        let lock_b = match lock(&self.api_b, &self.bob, alice, hash_lock, self.timeout_b)
+            .and_then(|lock_b| wait_for_output(&self.api_b, &alice, &lock_b.outpoint, timeout(&lock_b)).map(|_| lock_b)) {
+            Ok(lock_b) => lock_b,
+            Err(e) => {
+                info!("Bob's lock failed ({}), Alice takes her coin back", e);
+                return refund(&self.api_a, &lock_a, &self.alice).and(Err(e));
+            }
+        };
+
+        info!("Alice claims Bob's coin on {}, revealing the secret", self.api_b);
+        spend(&self.api_b, &lock_b, &self.alice, vec![Witness::Preimage(secret.to_vec())])?;
+
+        let preimage = loop {
+            match client::call(&self.api_b, &format!("/htlc/preimage?hash={}", hash_lock)) {
+                Ok(preimage) => break hex::decode(preimage).map_err(|e| e.to_string())?,
+                Err(_) if tip_height(&self.api_b)? + 1 < timeout(&lock_b) => sleep(),
+                Err(e) => {
+                    info!("Alice did not claim in time ({}), Bob takes his coin back", e);
+                    return refund(&self.api_b, &lock_b, &self.bob).and(Err(e));
+                }
+            }
+        };
+
+        info!("Bob claims Alice's coin on {} with the revealed secret", self.api_a);
+        let claimed = spend(&self.api_a, &lock_a, &self.bob, vec![Witness::Preimage(preimage)])?;
+        wait_for_output(&self.api_a, &bob, &claimed, timeout(&lock_a))?;
+        info!("Swap complete");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::crypto::key_pair;
+    use crate::transaction::State;
+
+    #[test]
+    fn claim_and_refund() {
+        let alice = key_pair::random();
+        let bob = key_pair::random();
+        let coin = (
+            TransactionInput { txid: 0, prev_tx: H256::from([1u8; 32]) },
+            TransactionOutput { recipient: address(&alice), value: 10_000, script: Script::PayToAddress },
+        );
+        let mut state: State = vec![coin.clone()].into_iter().collect();
+        let secret = [7u8; 32];
+        let (tx, lock) = lock_transaction(coin.clone(), &alice, address(&bob), hash_preimage(&secret), 10).unwrap();
+        assert!(Blockchain::transaction_check(&tx, &state, 1));
+        state.insert(lock.outpoint, lock.output.clone());
+
+        // before the timeout, only Bob can claim, and only with the secret:
+        let claim = spend_transaction(&lock, &bob, vec![Witness::Preimage(secret.to_vec())]).unwrap();
+        assert!(Blockchain::transaction_check(&claim, &state, 9));
+        assert!(!Blockchain::transaction_check(&claim, &state, 10));
+        let wrong = spend_transaction(&lock, &bob, vec![Witness::Preimage(vec![0u8; 32])]).unwrap();
+        assert!(!Blockchain::transaction_check(&wrong, &state, 9));
+        assert_eq!(claim.raw.TransactionOutput[0].value, 10_000 - 2 * FEE);
+
+        // from the timeout on, only Alice can take the coin back:
+        let refund = spend_transaction(&lock, &alice, vec![]).unwrap();
+        assert!(!Blockchain::transaction_check(&refund, &state, 9));
+        assert!(Blockchain::transaction_check(&refund, &state, 10));
+
+        // a coin that cannot pay the fee is refused rather than underflowing:
+        let small = (coin.0, TransactionOutput { value: FEE - 1, ..coin.1 });
+        assert!(lock_transaction(small, &alice, address(&bob), hash_preimage(&secret), 10).is_err());
+        let small_lock = Lock { output: TransactionOutput { value: FEE - 1, ..lock.output.clone() }, ..lock };
+        assert!(spend_transaction(&small_lock, &bob, vec![]).is_err());
+    }
+
+    #[test]
+    fn timeouts_are_checked() {
+        let swap = Swap {
+            api_a: String::new(),
+            api_b: String::new(),
+            alice: key_pair::random(),
+            bob: key_pair::random(),
+            timeout_a: 10,
+            timeout_b: 10,
+        };
+        assert!(swap.run().is_err());
+    }
+}
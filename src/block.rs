@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
 use crate::transaction::SignedTransaction as Transaction;
+use crate::chain_spec::ChainSpec;
 
 /// The block header
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,11 +36,12 @@ fn default_difficulty() -> [u8; 32] {
 }
 
 impl Block {
-    /// Construct the (totally deterministic) genesis block
-    pub fn genesis() -> Block {
+    /// Construct the (totally deterministic) genesis block of the chain described by `spec`.
+    /// Its parent field holds the chain id.
+    pub fn genesis(spec: &ChainSpec) -> Block {
         let transactions: Vec<Transaction> = vec![];
         let header = Header {
-            parent: Default::default(),
+            parent: spec.id(),
             nonce: 0,
             difficulty: default_difficulty().into(),
             timestamp: 0,
@@ -73,17 +75,25 @@ impl Hashable for Block {
 #[cfg(any(test, test_utilities))]
 pub mod test {
     use super::*;
+    use crate::clock::{Clock, SystemClock};
     use crate::crypto::hash::H256;
     use crate::crypto::merkle::MerkleTree;
+    use rand::Rng;
 
     pub fn generate_random_block(parent: &H256) -> Block {
+        generate_block(parent, &mut rand::thread_rng(), &SystemClock)
+    }
+
+    /// Generate a block on `parent` with a nonce drawn from `rng` and a timestamp from `clock`,
+    /// so that a seeded `rng` and a manual `clock` reproduce the same blocks
+    pub fn generate_block<R: Rng>(parent: &H256, rng: &mut R, clock: &dyn Clock) -> Block {
         let transactions: Vec<Transaction> = vec![Default::default()];
         let root = MerkleTree::new(&transactions).root();
         let header = Header {
             parent: *parent,
-            nonce: rand::random(),
+            nonce: rng.gen(),
             difficulty: default_difficulty().into(),
-            timestamp: rand::random(),
+            timestamp: clock.now_ms(),
             merkle_root: root,
         };
         let content = Content { transactions };
@@ -0,0 +1,93 @@
+use crate::address::H160;
+use crate::block::{Block, Content, Header};
+use crate::blockchain::{Blockchain, BLOCK_REWARD};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::mempool::Mempool;
+use crate::transaction::{Script, SignedTransaction as Transaction, Transaction as RawTransaction, TransactionOutput};
+
+/// The most transactions in a mined block, including the coinbase
+pub const MAX_BLOCK_TRANSACTIONS: usize = 20;
+
+/// The coinbase of a block at `height`, splitting `value` among `payouts` in proportion to their
+/// weights, the rounding remainder going to the first. There must be a payout of positive weight,
+/// so that the reward is not burned.
+fn coinbase(height: u64, value: u64, payouts: &[(H160, u64)]) -> Transaction {
+    let total_weight: u64 = payouts.iter().map(|(_, weight)| weight).sum();
+    assert!(total_weight > 0, "a coinbase must pay someone");
+    let mut outputs: Vec<TransactionOutput> = payouts.iter()
+        .map(|(recipient, weight)| TransactionOutput {
+            recipient: *recipient,
+            value: (value as u128 * *weight as u128 / total_weight as u128) as u64,
+            script: Script::PayToAddress,
+        })
+        .collect();
+    outputs[0].value += value - outputs.iter().map(|output| output.value).sum::<u64>();
+    outputs.retain(|output| output.value > 0);
+    // commit to the height, so that coinbases paying the same outputs have distinct hashes:
+    outputs.push(TransactionOutput {
+        recipient: H160::default(),
+        value: 0,
+        script: Script::DataCarrier(height.to_be_bytes().to_vec()),
+    });
+    Transaction {
+        raw: RawTransaction { TransactionInput: vec![], TransactionOutput: outputs },
+        ..Default::default()
+    }
+}
+
+/// A candidate block on the tip of the longest chain, with everything but its nonce
+pub struct BlockTemplate {
+    pub header: Header,
+    pub content: Content,
+    /// The revision of the mempool the transactions were selected from
+    mempool_revision: u64,
+}
+
+impl BlockTemplate {
+    /// Assemble a template on the tip of `blockchain`, with the best transactions of `mempool`.
+    /// The transactions stay in the mempool until a block containing them is connected.
+    /// The coinbase splits the block reward and fees among `payouts` in proportion to their weights.
+    pub fn assemble(blockchain: &Blockchain, mempool: &Mempool, payouts: &[(H160, u64)], timestamp: u128) -> Self {
+        let parent = blockchain.tip();
+        let difficulty = blockchain.get_block(&parent).header.difficulty;
+        let selected = mempool.select_packages(MAX_BLOCK_TRANSACTIONS - 1);
+        let fees: u64 = selected.iter().map(|tx| mempool.get_entry(&tx.hash()).unwrap().fee).sum();
+        let coinbase = coinbase(blockchain.get_height(&parent) + 1, BLOCK_REWARD + fees, payouts);
+        let mut transactions: Vec<Transaction> = vec![coinbase];
+        transactions.extend(selected);
+        // the header commits to every transaction of the block:
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let header = Header {
+            parent,
+            nonce: 0,
+            difficulty,
+            timestamp,
+            merkle_root,
+        };
+        BlockTemplate {
+            header,
+            content: Content { transactions },
+            mempool_revision: mempool.revision(),
+        }
+    }
+
+    /// Whether the tip or the mempool changed since the template was assembled
+    pub fn is_stale(&self, blockchain: &Blockchain, mempool: &Mempool) -> bool {
+        blockchain.tip() != self.header.parent || mempool.revision() != self.mempool_revision
+    }
+
+    /// The hash of the block with the given nonce
+    pub fn hash_with_nonce(&self, nonce: u32) -> H256 {
+        let mut header = self.header.clone();
+        header.nonce = nonce;
+        header.hash()
+    }
+
+    /// The block with the given nonce
+    pub fn block(&self, nonce: u32) -> Block {
+        let mut header = self.header.clone();
+        header.nonce = nonce;
+        Block { header, content: self.content.clone() }
+    }
+}
@@ -1,11 +1,23 @@
 use crate::block::Block;
 use crate::crypto::hash::{H256, Hashable};
-use std::collections::HashMap; 
-use crate::transaction::{SignedTransaction, State, Transaction, TransactionInput, TransactionOutput};
+use crate::crypto::merkle::MerkleTree;
+use std::collections::{HashMap, HashSet};
+use crate::transaction::{hash_preimage, Script, SignedTransaction, State, Transaction, TransactionInput, TransactionOutput, Witness, MAX_DATA_CARRIER_SIZE};
 use crate::crypto::key_pair;
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use rand::Rng;
 use crate::address::H160;
+use crate::chain_spec::ChainSpec;
+use crate::verifier::Signers;
+use serde::{Serialize, Deserialize};
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use crate::clock::{Clock, SystemClock};
+use std::sync::Arc;
+
+/// The value of each output of the initial coin offering
+pub const ICO_VALUE: u64 = 1_000_000;
+/// The value a block's coinbase may create, on top of the fees of the block's transactions
+pub const BLOCK_REWARD: u64 = 100_000;
 
 /// Whether the block is mined or received from the network
 pub enum BlockOrigin {
@@ -13,21 +25,53 @@ pub enum BlockOrigin {
     Received{delay_ms: u128},
 }
 
+/// Where a data-carrier output was committed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PayloadRecord {
+    pub block: H256,
+    pub height: u64,
+    /// The hash of the signed transaction
+    pub transaction: H256,
+    /// The index of the output in the transaction
+    pub output: u32,
+}
+
+/// A change of the tip of the longest chain
+#[derive(Debug, Clone, Copy)]
+pub struct TipChange {
+    pub tip: H256,
+    /// When the tip changed, in milliseconds since the Unix epoch
+    pub time: u128,
+}
+
 pub struct Blockchain {
     hash_to_block: HashMap<H256, Block>,
     hash_to_height: HashMap<H256, u64>,
     tip: H256,
     difficulty: H256,
-    orphan_buffer: HashMap<H256, Vec<Block>>,
+    /// Whether the proof of work of blocks is not checked (see `ChainSpec::regtest`)
+    regtest: bool,
+    /// Parentless blocks by their parent, with the valid signers of each of their transactions
+    orphan_buffer: HashMap<H256, Vec<(Block, Vec<Signers>)>>,
+    /// Data-carrier outputs of all blocks, including forks, by the SHA256 hash of their payload
+    hash_to_payloads: HashMap<H256, Vec<PayloadRecord>>,
+    tip_subscribers: Vec<Sender<TipChange>>,
+    /// The source of the times of tip changes
+    clock: Arc<dyn Clock>,
     // below are used for experiments:
     pub hash_to_origin: HashMap<H256, BlockOrigin>,
     pub hash_to_state: HashMap<H256, State>,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the default chain, only containing the genesis block
     pub fn new() -> Self {
-        let genesis_block = Block::genesis();
+        Self::with_spec(&ChainSpec::default())
+    }
+
+    /// Create a new blockchain of the chain described by `spec`, only containing the genesis block
+    pub fn with_spec(spec: &ChainSpec) -> Self {
+        let genesis_block = Block::genesis(spec);
         let genesis_hash = genesis_block.hash();
         let genesis_difficulty = genesis_block.header.difficulty;
         let mut hash_to_block = HashMap::new();
@@ -54,11 +98,12 @@ impl Blockchain {
             }
             let trans_input = TransactionInput{
                 txid: count,
-                prev_tx: H256::from([50u8; 32]),
+                prev_tx: spec.id(),
             };
             let trans_output = TransactionOutput{
                 recipient: H160::from_pubkey(&controlled_keypair.public_key().as_ref()),
-                value: H256::from([10u8; 32]),
+                value: ICO_VALUE,
+                script: Script::PayToAddress,
             };
             state.insert(trans_input, trans_output);
             count +=1;
@@ -70,7 +115,11 @@ impl Blockchain {
             hash_to_height,
             tip: genesis_hash,
             difficulty: genesis_difficulty,
+            regtest: spec.regtest,
             orphan_buffer: HashMap::new(),
+            hash_to_payloads: HashMap::new(),
+            tip_subscribers: Vec::new(),
+            clock: Arc::new(SystemClock),
             hash_to_origin: HashMap::new(),
             hash_to_state,
         }
@@ -84,12 +133,48 @@ impl Blockchain {
         let block_hash = block.hash();
         self.hash_to_block.insert(block_hash, block.clone());
         self.hash_to_height.insert(block_hash, height);
-        if height > *self.hash_to_height.get(&self.tip).unwrap() {
+        let new_tip = height > *self.hash_to_height.get(&self.tip).unwrap();
+        if new_tip {
             self.tip = block_hash;
         }
-        let state = self.hash_to_state.get(&parent_hash).unwrap();
-        self.process_all_transactions(block, &mut state.clone());
+        let mut state = self.hash_to_state.get(&parent_hash).unwrap().clone();
+        self.process_all_transactions(block, &mut state);
+        self.hash_to_state.insert(block_hash, state);
+        for tx in &block.content.transactions {
+            for (index, output) in tx.raw.TransactionOutput.iter().enumerate() {
+                if let Script::DataCarrier(data) = &output.script {
+                    self.hash_to_payloads.entry(hash_preimage(data)).or_default().push(PayloadRecord {
+                        block: block_hash,
+                        height,
+                        transaction: tx.hash(),
+                        output: index as u32,
+                    });
+                }
+            }
+        }
+        if new_tip {
+            self.notify_tip_change();
+        }
+    }
+
+    /// Take the times of tip changes from `clock` instead of the system clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Get notified of every change of the tip. A subscriber that has not received the last
+    /// notification yet is not sent a newer one, so it learns when the tip first changed.
+    pub fn subscribe_tip(&mut self) -> Receiver<TipChange> {
+        let (sender, receiver) = bounded(1);
+        self.tip_subscribers.push(sender);
+        receiver
+    }
 
+    fn notify_tip_change(&mut self) {
+        let change = TipChange { tip: self.tip, time: self.clock.now_ms() };
+        self.tip_subscribers.retain(|subscriber| {
+            !matches!(subscriber.try_send(change), Err(TrySendError::Disconnected(_)))
+        });
     }
 
     /// Get the last block's hash of the longest chain
@@ -108,6 +193,38 @@ impl Blockchain {
         hashes_backward.into_iter().rev().collect()
     }
 
+    /// Whether a block is in the longest chain
+    pub fn is_in_longest_chain(&self, hash: &H256) -> bool {
+        let height = match self.hash_to_height.get(hash) {
+            Some(height) => *height,
+            None => return false,
+        };
+        let mut curr_hash = self.tip;
+        while self.get_height(&curr_hash) > height {
+            curr_hash = self.get_block(&curr_hash).header.parent;
+        }
+        curr_hash == *hash
+    }
+
+    /// The blocks to disconnect (from `from` backward) and to connect (forward to `to`)
+    /// to move from one block to another through their last common ancestor
+    pub fn route(&self, from: &H256, to: &H256) -> (Vec<H256>, Vec<H256>) {
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        let (mut from, mut to) = (*from, *to);
+        while from != to {
+            if self.get_height(&from) >= self.get_height(&to) {
+                disconnected.push(from);
+                from = self.get_block(&from).header.parent;
+            } else {
+                connected.push(to);
+                to = self.get_block(&to).header.parent;
+            }
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
     pub fn get_block(&self, hash: &H256) -> &Block {
         self.hash_to_block.get(hash).unwrap()
     }
@@ -116,9 +233,25 @@ impl Blockchain {
         self.hash_to_block.contains_key(hash)
     }
 
-    /// Check if a block is consistent with PoW
+    /// Get the height of a block, the genesis block being at height 0
+    pub fn get_height(&self, hash: &H256) -> u64 {
+        *self.hash_to_height.get(hash).unwrap()
+    }
+
+    /// Check if a block is consistent with PoW. Under the regtest rule, any hash is good enough.
     pub fn pow_validity_check(&self, block: &Block) -> bool {
-        block.hash() <= block.header.difficulty && block.header.difficulty == self.difficulty
+        (self.regtest || block.hash() <= block.header.difficulty) && block.header.difficulty == self.difficulty
+    }
+
+    /// Whether blocks are valid without proof of work
+    pub fn is_regtest(&self) -> bool {
+        self.regtest
+    }
+
+    /// Check that a block has transactions and that its header commits to them
+    pub fn merkle_check(block: &Block) -> bool {
+        let transactions = &block.content.transactions;
+        !transactions.is_empty() && MerkleTree::new(transactions).root() == block.header.merkle_root
     }
 
     /// Check if a block's parent is in the blockchain
@@ -126,9 +259,127 @@ impl Blockchain {
         self.contains_block(&block.header.parent)
     }
 
-    /// Add a PoW valid, parentless block to the orphan buffer
-    pub fn add_to_orphan_buffer(&mut self, block: &Block) {
-        self.orphan_buffer.entry(block.header.parent).or_insert(vec![]).push(block.clone());
+    /// Check that a transaction is signed correctly, that all its inputs are distinct, in `state`
+    /// and may be spent by its signers in a block at `height`, and that the values of its inputs
+    /// are not less than those of its outputs
+    pub fn transaction_check(tx: &SignedTransaction, state: &State, height: u64) -> bool {
+        match tx.verified_signers() {
+            Some(signers) => Self::spending_check(tx, &signers, state, height),
+            None => false,
+        }
+    }
+
+    /// The part of `transaction_check` after signature verification, for a transaction whose
+    /// valid signers are `signers`
+    pub fn spending_check(tx: &SignedTransaction, signers: &[H160], state: &State, height: u64) -> bool {
+        let mut input_value: u64 = 0;
+        let mut spent = HashSet::new();
+        for input in &tx.raw.TransactionInput {
+            if !spent.insert(input) {
+                return false;
+            }
+            let output = match state.get(input) {
+                Some(output) => output,
+                None => return false,
+            };
+            if !output.can_spend(signers, &tx.witness, height) {
+                return false;
+            }
+            input_value = match input_value.checked_add(output.value) {
+                Some(v) => v,
+                None => return false,
+            };
+        }
+        if !Self::data_carrier_check(tx) {
+            return false;
+        }
+        let output_value = tx.raw.TransactionOutput.iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value));
+        match output_value {
+            Some(output_value) => input_value >= output_value,
+            None => false,
+        }
+    }
+
+    /// Check that no data-carrier output of a transaction, coinbase included, exceeds the size limit
+    fn data_carrier_check(tx: &SignedTransaction) -> bool {
+        tx.raw.TransactionOutput.iter().all(|output| match &output.script {
+            Script::DataCarrier(data) => data.len() <= MAX_DATA_CARRIER_SIZE,
+            _ => true,
+        })
+    }
+
+    /// The fee of a transaction that passes `spending_check` against `state`,
+    /// i.e. the values of its inputs minus those of its outputs
+    pub fn transaction_fee(tx: &SignedTransaction, state: &State) -> u64 {
+        let input_value: u64 = tx.raw.TransactionInput.iter().map(|input| state[input].value).sum();
+        let output_value: u64 = tx.raw.TransactionOutput.iter().map(|output| output.value).sum();
+        input_value - output_value
+    }
+
+    /// Check a transaction against the state of the longest chain, as if it went into the next block
+    pub fn tip_transaction_check(&self, tx: &SignedTransaction) -> bool {
+        match tx.verified_signers() {
+            Some(signers) => self.tip_spending_check(tx, &signers),
+            None => false,
+        }
+    }
+
+    /// The part of `tip_transaction_check` after signature verification
+    pub fn tip_spending_check(&self, tx: &SignedTransaction, signers: &[H160]) -> bool {
+        let height = self.get_height(&self.tip) + 1;
+        Self::spending_check(tx, signers, self.get_state(&self.tip), height)
+    }
+
+    /// Check the transactions of a parentful block against its parent's state.
+    /// The first transaction is the coinbase, which must not have any input, and whose outputs
+    /// are worth at most the block reward plus the fees of the block.
+    pub fn transaction_check_block(&self, block: &Block) -> bool {
+        let signers: Vec<_> = block.content.transactions.iter()
+            .map(|tx| tx.verified_signers())
+            .collect();
+        self.spending_check_block(block, &signers)
+    }
+
+    /// The part of `transaction_check_block` after signature verification, given the valid signers
+    /// of each transaction of the block (`None` if its signature is invalid)
+    pub fn spending_check_block(&self, block: &Block, signers: &[Option<Vec<H160>>]) -> bool {
+        assert_eq!(block.content.transactions.len(), signers.len(), "one set of signers per transaction");
+        let parent = block.header.parent;
+        let height = self.get_height(&parent) + 1;
+        let mut state = self.get_state(&parent).clone();
+        let mut transactions = block.content.transactions.iter().zip(signers);
+        let mut coinbase_value = 0u64;
+        if let Some((coinbase, _)) = transactions.next() {
+            if !coinbase.raw.TransactionInput.is_empty() || !Self::data_carrier_check(coinbase) {
+                return false;
+            }
+            coinbase_value = match coinbase.raw.TransactionOutput.iter()
+                .try_fold(0u64, |sum, output| sum.checked_add(output.value)) {
+                Some(value) => value,
+                None => return false,
+            };
+            Self::apply_transaction(coinbase, &mut state);
+        }
+        let mut fees = 0u64;
+        for (tx, signers) in transactions {
+            let valid = match signers {
+                Some(signers) => Self::spending_check(tx, signers, &state, height),
+                None => false,
+            };
+            if !valid {
+                return false;
+            }
+            fees += Self::transaction_fee(tx, &state);
+            Self::apply_transaction(tx, &mut state);
+        }
+        coinbase_value <= BLOCK_REWARD + fees
+    }
+
+    /// Add a PoW valid, parentless block to the orphan buffer, with the valid signers of each of its
+    /// transactions, so that its signatures need not be verified again once its parent arrives
+    pub fn add_to_orphan_buffer(&mut self, block: &Block, signers: Vec<Signers>) {
+        self.orphan_buffer.entry(block.header.parent).or_insert(vec![]).push((block.clone(), signers));
     }
 
     /// Insert a PoW valid, parentful block into the blockchain, and recursively do all its children.
@@ -140,13 +391,20 @@ impl Blockchain {
         self.insert(block);
         out_hashes.push(block.hash());
         if self.orphan_buffer.contains_key(&block.hash()) {
-            for child in self.orphan_buffer.remove(&block.hash()).unwrap() {
-                self.insert_recursively(&child, out_hashes);
+            for (child, signers) in self.orphan_buffer.remove(&block.hash()).unwrap() {
+                if self.spending_check_block(&child, &signers) {
+                    self.insert_recursively(&child, out_hashes);
+                }
             }
         }
     }
 
     pub fn process_one_transaction(&mut self, transaction: &SignedTransaction, state: &mut State){
+        Self::apply_transaction(transaction, state);
+    }
+
+    /// Update `state` by spending the inputs of `transaction` and adding its outputs
+    pub fn apply_transaction(transaction: &SignedTransaction, state: &mut State){
         //remove inputs
         let tx = &transaction.raw;
         let tx_input = &tx.TransactionInput;
@@ -158,7 +416,10 @@ impl Blockchain {
         }
         let mut count = 0;
         for each_tx in tx_output{
-            state.insert(TransactionInput{txid: count, prev_tx: tx.hash()}, each_tx.clone());
+            // data carriers are unspendable, so they never enter the state
+            if !matches!(each_tx.script, Script::DataCarrier(_)) {
+                state.insert(TransactionInput{txid: count, prev_tx: tx.hash()}, each_tx.clone());
+            }
             count+=1;
         }
     }
@@ -172,6 +433,26 @@ impl Blockchain {
     pub fn get_state(&self, hash: &H256) -> &State {
         self.hash_to_state.get(hash).unwrap()
     }
+    /// Find a hash lock preimage revealed by a transaction in the longest chain
+    pub fn find_preimage(&self, hash_lock: &H256) -> Option<Vec<u8>> {
+        self.all_blocks_in_longest_chain().iter()
+            .flat_map(|hash| self.get_block(hash).content.transactions.iter())
+            .flat_map(|tx| tx.witness.iter())
+            .find_map(|witness| match witness {
+                Witness::Preimage(preimage) if hash_preimage(preimage) == *hash_lock => Some(preimage.clone()),
+                _ => None,
+            })
+    }
+
+    /// Find the data-carrier outputs in the longest chain whose payload hashes to `payload_hash`
+    pub fn find_payload(&self, payload_hash: &H256) -> Vec<PayloadRecord> {
+        self.hash_to_payloads.get(payload_hash).into_iter()
+            .flatten()
+            .filter(|record| self.is_in_longest_chain(&record.block))
+            .cloned()
+            .collect()
+    }
+
     pub fn block_count(&self) -> usize {
         self.hash_to_block.len()
     }
@@ -195,8 +476,11 @@ impl Blockchain {
 #[cfg(any(test, test_utilities))]
 mod tests {
     use super::*;
-    use crate::block::test::generate_random_block;
+    use crate::block::test::{generate_block, generate_random_block};
+    use crate::clock::ManualClock;
     use crate::crypto::hash::Hashable;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn insert_one() {
@@ -207,4 +491,180 @@ mod tests {
         assert_eq!(blockchain.tip(), block.hash());
 
     }
+
+    #[test]
+    fn reproducible_chain() {
+        fn build(seed: u64) -> Vec<H256> {
+            let mut blockchain = Blockchain::new();
+            let mut rng = StdRng::seed_from_u64(seed);
+            let clock = ManualClock::new(1_000_000);
+            for _ in 0..5 {
+                let block = generate_block(&blockchain.tip(), &mut rng, &clock);
+                blockchain.insert(&block);
+                clock.advance(10_000);
+            }
+            blockchain.all_blocks_in_longest_chain()
+        }
+        assert_eq!(build(7), build(7));
+        assert_ne!(build(7), build(8));
+    }
+
+    #[test]
+    fn tip_notifications() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tip_changes = blockchain.subscribe_tip();
+        blockchain.set_clock(Arc::new(ManualClock::new(42)));
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let change = tip_changes.try_recv().unwrap();
+        assert_eq!((change.tip, change.time), (block.hash(), 42));
+        // a fork as long as the longest chain does not change the tip:
+        blockchain.insert(&generate_random_block(&genesis_hash));
+        assert!(tip_changes.try_recv().is_err());
+        // only the first of several changes is pending:
+        let child = generate_random_block(&block.hash());
+        blockchain.insert(&child);
+        blockchain.insert(&generate_random_block(&child.hash()));
+        assert_eq!(tip_changes.try_recv().unwrap().tip, child.hash());
+        assert!(tip_changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn data_carrier_index() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let payload = b"artifact".to_vec();
+        let mut block = generate_random_block(&genesis_hash);
+        block.content.transactions[0].raw.TransactionOutput.push(TransactionOutput {
+            recipient: H160::default(),
+            value: 0,
+            script: Script::DataCarrier(payload.clone()),
+        });
+        blockchain.insert(&block);
+        let records = blockchain.find_payload(&hash_preimage(&payload));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].block, block.hash());
+        assert_eq!(records[0].height, 1);
+        assert!(blockchain.get_state(&block.hash()).values().all(|output| output.script == Script::PayToAddress));
+
+        // a longer fork without the payload makes it unconfirmed again:
+        let fork_1 = generate_random_block(&genesis_hash);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_1);
+        blockchain.insert(&fork_2);
+        assert!(blockchain.find_payload(&hash_preimage(&payload)).is_empty());
+    }
+
+    #[test]
+    fn coinbase_data_carrier() {
+        let blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.transactions[0].raw.TransactionOutput.push(TransactionOutput {
+            recipient: H160::default(),
+            value: 0,
+            script: Script::DataCarrier(vec![0; MAX_DATA_CARRIER_SIZE]),
+        });
+        assert!(blockchain.transaction_check_block(&block));
+        // the coinbase is held to the data-carrier size limit too:
+        block.content.transactions[0].raw.TransactionOutput.last_mut().unwrap().script = Script::DataCarrier(vec![0; MAX_DATA_CARRIER_SIZE + 1]);
+        assert!(!blockchain.transaction_check_block(&block));
+    }
+
+    #[test]
+    fn duplicate_inputs() {
+        let blockchain = Blockchain::new();
+        let owner = Ed25519KeyPair::from_seed_unchecked(&[100u8; 32]).unwrap();
+        let coin = TransactionInput { txid: 1, prev_tx: ChainSpec::default().id() };
+        let pay = |inputs: Vec<TransactionInput>, value: u64| SignedTransaction::from_raw(Transaction {
+            TransactionInput: inputs,
+            TransactionOutput: vec![TransactionOutput { recipient: H160::default(), value, script: Script::PayToAddress }],
+        }, &owner);
+        assert!(blockchain.tip_transaction_check(&pay(vec![coin], ICO_VALUE)));
+        // spending the same coin twice in one transaction does not double its value:
+        let double = pay(vec![coin, coin], 2 * ICO_VALUE);
+        assert!(!blockchain.tip_transaction_check(&double));
+
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.transactions.push(double);
+        assert!(!blockchain.transaction_check_block(&block));
+    }
+
+    #[test]
+    fn orphan_signers() {
+        let mut blockchain = Blockchain::new();
+        let owner = Ed25519KeyPair::from_seed_unchecked(&[100u8; 32]).unwrap();
+        let parent = generate_random_block(&blockchain.tip());
+        let orphan = |signed: bool| {
+            let mut block = generate_random_block(&parent.hash());
+            block.content.transactions.push(SignedTransaction::from_raw(Transaction {
+                TransactionInput: vec![TransactionInput { txid: 1, prev_tx: ChainSpec::default().id() }],
+                TransactionOutput: vec![TransactionOutput { recipient: H160::default(), value: ICO_VALUE, script: Script::PayToAddress }],
+            }, &owner));
+            let signers: Vec<_> = block.content.transactions.iter()
+                .map(|tx| if signed { tx.verified_signers() } else { None })
+                .collect();
+            (block, signers)
+        };
+        let (valid, valid_signers) = orphan(true);
+        let (invalid, invalid_signers) = orphan(false);
+        blockchain.add_to_orphan_buffer(&valid, valid_signers);
+        blockchain.add_to_orphan_buffer(&invalid, invalid_signers);
+
+        // the orphans are checked against the signers they were buffered with once their parent arrives:
+        let mut hashes = vec![];
+        blockchain.insert_recursively(&parent, &mut hashes);
+        assert_eq!(hashes, vec![parent.hash(), valid.hash()]);
+        assert_eq!(blockchain.tip(), valid.hash());
+    }
+
+    #[test]
+    fn regtest_rule() {
+        let blockchain = Blockchain::new();
+        let regtest = Blockchain::with_spec(&ChainSpec { regtest: true, ..ChainSpec::default() });
+        let mut block = generate_random_block(&blockchain.tip());
+        while block.hash() <= block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        assert!(!blockchain.pow_validity_check(&block));
+        assert!(regtest.pow_validity_check(&block));
+        block.header.difficulty = [0xffu8; 32].into();
+        assert!(!regtest.pow_validity_check(&block));
+    }
+
+    #[test]
+    fn coinbase_value() {
+        let blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.transactions[0].raw.TransactionOutput.push(TransactionOutput {
+            recipient: H160::default(),
+            value: BLOCK_REWARD,
+            script: Script::PayToAddress,
+        });
+        assert!(blockchain.transaction_check_block(&block));
+        block.content.transactions[0].raw.TransactionOutput[0].value += 1;
+        assert!(!blockchain.transaction_check_block(&block));
+    }
+
+    #[test]
+    fn merkle_commitment() {
+        let block = generate_random_block(&Blockchain::new().tip());
+        assert!(Blockchain::merkle_check(&block));
+
+        let mut changed = block.clone();
+        changed.content.transactions[0].raw.TransactionOutput.push(TransactionOutput {
+            recipient: H160::default(),
+            value: ICO_VALUE,
+            script: Script::PayToAddress,
+        });
+        assert!(!Blockchain::merkle_check(&changed));
+
+        let mut appended = block.clone();
+        appended.content.transactions.push(Default::default());
+        assert!(!Blockchain::merkle_check(&appended));
+
+        let mut emptied = block;
+        emptied.content.transactions.clear();
+        assert!(!Blockchain::merkle_check(&emptied));
+    }
 }
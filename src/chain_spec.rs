@@ -0,0 +1,47 @@
+use crate::crypto::hash::H256;
+
+/// Parameters that tell one local chain apart from another, so that several independent chains
+/// can run side by side (e.g. for cross-chain atomic swaps)
+#[derive(Debug, Clone)]
+pub struct ChainSpec {
+    pub name: String,
+    /// Whether blocks are valid without proof of work, for simulated mining experiments
+    pub regtest: bool,
+}
+
+impl ChainSpec {
+    pub fn new(name: &str) -> Self {
+        ChainSpec { name: name.to_string(), regtest: false }
+    }
+
+    /// The chain id, committed to by the genesis block and the initial coin offering,
+    /// so that blocks and transactions of one chain are never valid on another. A regtest chain
+    /// never shares its id with a chain of the same name requiring proof of work.
+    pub fn id(&self) -> H256 {
+        let mut preimage = self.name.as_bytes().to_vec();
+        if self.regtest {
+            preimage.extend_from_slice(b"\0regtest");
+        }
+        ring::digest::digest(&ring::digest::SHA256, &preimage).into()
+    }
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        ChainSpec::new("main")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regtest_id() {
+        let main = ChainSpec::new("main");
+        let regtest = ChainSpec { regtest: true, ..ChainSpec::new("main") };
+        assert_ne!(main.id(), regtest.id());
+        assert_ne!(main.id(), ChainSpec::new("other").id());
+        assert_eq!(main.id(), ChainSpec::default().id());
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of the current time, so that time-stamped data (e.g. block headers) can be
+/// reproduced by running against a controlled clock
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the UNIX epoch
+    fn now_ms(&self) -> u128;
+}
+
+/// The wall clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u128 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+    }
+}
+
+/// A clock that only moves when told to
+pub struct ManualClock {
+    now_ms: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now_ms: u64) -> Self {
+        ManualClock { now_ms: AtomicU64::new(now_ms) }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u128 {
+        self.now_ms.load(Ordering::SeqCst) as u128
+    }
+}
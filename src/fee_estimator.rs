@@ -0,0 +1,173 @@
+use crate::crypto::hash::H256;
+use crate::persistence::write_atomically;
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// The largest confirmation target, in blocks, that can be estimated
+pub const MAX_TARGET: u64 = 25;
+/// The lower bound of the lowest fee-rate bucket, in fee per 1000 bytes
+const MIN_BUCKET_FEE_RATE: f64 = 1.0;
+/// The upper bound of the highest fee-rate bucket, in fee per 1000 bytes
+const MAX_BUCKET_FEE_RATE: f64 = 10_000_000.0;
+/// The ratio between the lower bounds of successive buckets
+const BUCKET_SPACING: f64 = 1.2;
+/// How much the past observations weigh after each block, so that recent blocks matter most
+const DECAY: f64 = 0.998;
+/// The fraction of transactions of a fee-rate range that must confirm within the target
+const SUCCESS_THRESHOLD: f64 = 0.85;
+/// The (decayed) number of transactions a fee-rate range needs before it is judged
+const MIN_SAMPLES: f64 = 2.0;
+
+/// The lower bounds of the fee-rate buckets, in fee per 1000 bytes
+fn bucket_bounds() -> Vec<u64> {
+    let mut bounds = vec![];
+    let mut bound = MIN_BUCKET_FEE_RATE;
+    while bound <= MAX_BUCKET_FEE_RATE {
+        bounds.push(bound as u64);
+        bound *= BUCKET_SPACING;
+    }
+    bounds.dedup();
+    bounds
+}
+
+/// Estimates the fee rate a transaction needs to confirm within a target number of blocks, from
+/// how long the transactions entering this node's mempool took to confirm, grouped by fee rate
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeeEstimator {
+    bounds: Vec<u64>,
+    /// The (decayed) number of confirmed transactions in each bucket
+    confirmed: Vec<f64>,
+    /// `confirmed_within[bucket][target - 1]` is the (decayed) number of transactions in `bucket`
+    /// confirmed within `target` blocks
+    confirmed_within: Vec<Vec<f64>>,
+    /// The bucket and the tip height at arrival of each unconfirmed transaction being tracked
+    tracked: HashMap<H256, (usize, u64)>,
+    best_height: u64,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeeEstimator {
+    pub fn new() -> Self {
+        let bounds = bucket_bounds();
+        FeeEstimator {
+            confirmed: vec![0.0; bounds.len()],
+            confirmed_within: vec![vec![0.0; MAX_TARGET as usize]; bounds.len()],
+            bounds,
+            tracked: HashMap::new(),
+            best_height: 0,
+        }
+    }
+
+    fn bucket(&self, fee_rate: u64) -> usize {
+        match self.bounds.binary_search(&fee_rate) {
+            Ok(bucket) => bucket,
+            Err(0) => 0,
+            Err(next) => next - 1,
+        }
+    }
+
+    /// Start tracking a transaction paying `fee_rate` per 1000 bytes, which entered the mempool
+    /// when the tip was at `height`
+    pub fn track(&mut self, hash: H256, fee_rate: u64, height: u64) {
+        let bucket = self.bucket(fee_rate);
+        self.tracked.entry(hash).or_insert((bucket, height));
+    }
+
+    /// Stop tracking a transaction that left the mempool without being confirmed
+    pub fn forget(&mut self, hash: &H256) {
+        self.tracked.remove(hash);
+    }
+
+    /// Record the tracked transactions confirmed by a block at `height`
+    pub fn process_block(&mut self, height: u64, hashes: impl Iterator<Item = H256>) {
+        for bucket in 0..self.bounds.len() {
+            self.confirmed[bucket] *= DECAY;
+            for within in self.confirmed_within[bucket].iter_mut() {
+                *within *= DECAY;
+            }
+        }
+        for hash in hashes {
+            if let Some((bucket, arrival_height)) = self.tracked.remove(&hash) {
+                let blocks = height.saturating_sub(arrival_height).max(1);
+                self.confirmed[bucket] += 1.0;
+                for target in blocks..=MAX_TARGET {
+                    self.confirmed_within[bucket][target as usize - 1] += 1.0;
+                }
+            }
+        }
+        self.best_height = self.best_height.max(height);
+    }
+
+    /// The lowest fee rate, per 1000 bytes, at which at least `SUCCESS_THRESHOLD` of the transactions
+    /// confirmed within `target` blocks, counting the tracked transactions still waiting after
+    /// `target` blocks as failures. `None` if there is not enough data.
+    pub fn estimate(&self, target: u64) -> Option<u64> {
+        if target == 0 || target > MAX_TARGET {
+            return None;
+        }
+        let mut waiting = vec![0.0; self.bounds.len()];
+        for (bucket, arrival_height) in self.tracked.values() {
+            if self.best_height.saturating_sub(*arrival_height) > target {
+                waiting[*bucket] += 1.0;
+            }
+        }
+        // group buckets from the highest fee rate down until each group has enough data,
+        // and stop at the first group confirming too slowly:
+        let mut estimate = None;
+        let (mut total, mut within) = (0.0, 0.0);
+        for bucket in (0..self.bounds.len()).rev() {
+            total += self.confirmed[bucket] + waiting[bucket];
+            within += self.confirmed_within[bucket][target as usize - 1];
+            if total >= MIN_SAMPLES {
+                if within / total < SUCCESS_THRESHOLD {
+                    break;
+                }
+                estimate = Some(self.bounds[bucket]);
+                total = 0.0;
+                within = 0.0;
+            }
+        }
+        estimate
+    }
+
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        let data = bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomically(path, &data)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+        bincode::deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_from_confirmations() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate(1), None);
+        // transactions paying 5000 confirm in the next block, those paying 100 never do:
+        for i in 0..10u8 {
+            estimator.track(H256::from([i; 32]), 5_000, 0);
+            estimator.track(H256::from([100 + i; 32]), 100, 0);
+        }
+        estimator.process_block(1, (0..10u8).map(|i| H256::from([i; 32])));
+        estimator.process_block(2, std::iter::empty());
+        estimator.process_block(3, std::iter::empty());
+
+        let estimate = estimator.estimate(1).unwrap();
+        assert!(estimate > 100 && estimate <= 5_000);
+        assert_eq!(estimator.estimate(MAX_TARGET + 1), None);
+    }
+}
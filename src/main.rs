@@ -12,6 +12,18 @@ pub mod transaction;
 pub mod address;
 pub mod mempool;
 pub mod transaction_generator;
+pub mod transaction_builder;
+pub mod chain_spec;
+pub mod clock;
+pub mod atomic_swap;
+pub mod payment_channel;
+pub mod verifier;
+pub mod policy;
+pub mod persistence;
+pub mod pool;
+pub mod work;
+pub mod fee_estimator;
+pub mod block_template;
 
 use clap::clap_app;
 use crossbeam::channel;
@@ -27,8 +39,19 @@ use std::time;
 use std::sync::{Arc, Mutex};
 use crate::blockchain::Blockchain;
 use crate::transaction_generator::TransactionGenerator;
-use ring::signature::Ed25519KeyPair;
+use rand::rngs::StdRng;
+use rand::{FromEntropy, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
 use crate::crypto::key_pair;
+use crate::address::H160;
+use crate::chain_spec::ChainSpec;
+use crate::clock::{Clock, SystemClock};
+use crate::atomic_swap::Swap;
+use crate::payment_channel::ChannelManager;
+use crate::policy::Policy;
+use crate::persistence::Persistence;
+use crate::pool::{Pool, DEFAULT_POOL_WINDOW};
+use crate::work::{Worker, WorkManager};
 
 fn main() {
     // parse command line arguments
@@ -40,7 +63,45 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg verifier_workers: --("verifier-workers") [INT] default_value("4") "Sets the number of threads verifying transaction signatures")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching for nonces")
+     (@arg pool_share_factor: --("pool-share-factor") [INT] "Runs a mining pool for external workers, accepting shares with a target this many times easier than blocks")
+     (@arg pool_window: --("pool-window") [INT] "Sets the number of last shares a pool splits each block reward among")
+     (@arg seed: --seed [INT] "Seeds the random choices of the miner and the transaction generator, for reproducible runs")
      (@arg account_index: -i [INT] default_value("0") "Sets the index (0/100/200) of the pre-set keypairs in control")
+     (@arg dust_threshold: --("dust-threshold") [INT] "Sets the smallest output value this node relays")
+     (@arg max_tx_size: --("max-tx-size") [INT] "Sets the largest transaction size in bytes this node relays")
+     (@arg max_tx_inputs: --("max-tx-inputs") [INT] "Sets the largest number of inputs of a transaction this node relays")
+     (@arg max_tx_outputs: --("max-tx-outputs") [INT] "Sets the largest number of outputs of a transaction this node relays")
+     (@arg min_relay_fee_rate: --("min-relay-fee-rate") [INT] "Sets the smallest fee per 1000 bytes of a transaction this node relays")
+     (@arg max_mempool_size: --("max-mempool-size") [INT] "Sets the largest total size of the transactions in the mempool, in bytes")
+     (@arg max_ancestors: --("max-ancestors") [INT] "Sets the largest number of unconfirmed ancestors of a mempool transaction, itself included")
+     (@arg max_ancestor_size: --("max-ancestor-size") [INT] "Sets the largest total size of a mempool transaction and its unconfirmed ancestors, in bytes")
+     (@arg max_descendants: --("max-descendants") [INT] "Sets the largest number of descendants of a mempool transaction, itself included")
+     (@arg max_descendant_size: --("max-descendant-size") [INT] "Sets the largest total size of a mempool transaction and its descendants, in bytes")
+     (@arg replace_by_fee: --("replace-by-fee") "Lets transactions paying a higher fee replace conflicting mempool transactions")
+     (@arg replacement_fee_increment: --("replacement-fee-increment") [INT] "Sets the fee per 1000 bytes a replacement must add to the transactions it replaces")
+     (@arg mempool_expiry: --("mempool-expiry") [SECONDS] "Sets how long a transaction may stay unconfirmed in the mempool")
+     (@arg max_pending_per_sender: --("max-pending-per-sender") [INT] "Sets the largest number of mempool transactions signed by the same address")
+     (@arg max_data_carrier_size: --("max-data-carrier-size") [INT] "Sets the largest total data-carrier payload of a transaction this node relays, in bytes")
+     (@arg standard_scripts: --("standard-scripts") [KINDS] "Sets the comma-separated kinds of output scripts this node relays")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where the node saves its state (e.g. pending transactions) across restarts, every minute and on SIGINT, SIGTERM or /node/shutdown")
+     (@arg chain: --chain [NAME] default_value("main") "Sets the name of the chain to run, so that independent chains have distinct genesis blocks")
+     (@arg regtest: --regtest "Accepts blocks without proof of work, so that the miner can simulate block discovery")
+     (@subcommand swap =>
+      (about: "Runs a cross-chain atomic swap between two nodes on different chains, then exits")
+      (@arg api_a: --("api-a") <ADDR> "Sets the API address of a node on chain A, where Alice pays Bob")
+      (@arg api_b: --("api-b") <ADDR> "Sets the API address of a node on chain B, where Bob pays Alice")
+      (@arg alice: --alice [INT] default_value("0") "Sets the index of Alice's pre-set keypair")
+      (@arg bob: --bob [INT] default_value("100") "Sets the index of Bob's pre-set keypair")
+      (@arg timeout_a: --("timeout-a") [INT] default_value("20") "Sets the number of blocks before Alice can take back her coin")
+      (@arg timeout_b: --("timeout-b") [INT] default_value("10") "Sets the number of blocks before Bob can take back his coin")
+     )
+     (@subcommand work =>
+      (about: "Runs an external hashing process mining on the block templates of a node")
+      (@arg api: --api [ADDR] default_value("127.0.0.1:7000") "Sets the API address of the node handing out work")
+      (@arg name: --name [NAME] default_value("worker") "Sets the name the node tracks this worker's work under, which must be its payout address for a pool")
+     )
     )
     .get_matches();
 
@@ -48,6 +109,41 @@ fn main() {
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
+    // run the atomic swap helper instead of a node
+    if let Some(matches) = matches.subcommand_matches("swap") {
+        let parse_int = |name: &str| matches.value_of(name).unwrap().parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", name, e);
+            process::exit(1);
+        });
+        let swap = Swap {
+            api_a: matches.value_of("api_a").unwrap().to_string(),
+            api_b: matches.value_of("api_b").unwrap().to_string(),
+            alice: Ed25519KeyPair::from_seed_unchecked(&[parse_int("alice") as u8; 32]).unwrap(),
+            bob: Ed25519KeyPair::from_seed_unchecked(&[parse_int("bob") as u8; 32]).unwrap(),
+            timeout_a: parse_int("timeout_a"),
+            timeout_b: parse_int("timeout_b"),
+        };
+        if let Err(e) = swap.run() {
+            error!("Swap failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // run an external hashing process instead of a node
+    if let Some(matches) = matches.subcommand_matches("work") {
+        let worker = Worker {
+            api: matches.value_of("api").unwrap().to_string(),
+            name: matches.value_of("name").unwrap().to_string(),
+            clock: Arc::new(SystemClock),
+        };
+        if let Err(e) = worker.run() {
+            error!("Worker failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -75,10 +171,67 @@ fn main() {
     let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
     server_ctx.start().unwrap();
 
+    // the source of timestamps, shared by everything that stamps or ages data
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+
     // create the Blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let chain_spec = ChainSpec {
+        regtest: matches.is_present("regtest"),
+        ..ChainSpec::new(matches.value_of("chain").unwrap())
+    };
+    let mut blockchain = Blockchain::with_spec(&chain_spec);
+    blockchain.set_clock(Arc::clone(&clock));
+    let blockchain = Arc::new(Mutex::new(blockchain));
+
+    // parse the relay policy, keeping the defaults for missing options
+    fn parse_policy_value<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str, default: T) -> T
+    where T::Err: std::fmt::Display {
+        match matches.value_of(name) {
+            Some(value) => value.parse::<T>().unwrap_or_else(|e| {
+                error!("Error parsing {}: {}", name, e);
+                process::exit(1);
+            }),
+            None => default,
+        }
+    }
+    let default_policy = Policy::default();
+    let policy = Policy {
+        dust_threshold: parse_policy_value(&matches, "dust_threshold", default_policy.dust_threshold),
+        max_tx_size: parse_policy_value(&matches, "max_tx_size", default_policy.max_tx_size),
+        max_inputs: parse_policy_value(&matches, "max_tx_inputs", default_policy.max_inputs),
+        max_outputs: parse_policy_value(&matches, "max_tx_outputs", default_policy.max_outputs),
+        min_relay_fee_rate: parse_policy_value(&matches, "min_relay_fee_rate", default_policy.min_relay_fee_rate),
+        max_data_carrier_size: parse_policy_value(&matches, "max_data_carrier_size", default_policy.max_data_carrier_size),
+        max_mempool_size: parse_policy_value(&matches, "max_mempool_size", default_policy.max_mempool_size),
+        max_ancestors: parse_policy_value(&matches, "max_ancestors", default_policy.max_ancestors),
+        max_ancestor_size: parse_policy_value(&matches, "max_ancestor_size", default_policy.max_ancestor_size),
+        max_descendants: parse_policy_value(&matches, "max_descendants", default_policy.max_descendants),
+        max_descendant_size: parse_policy_value(&matches, "max_descendant_size", default_policy.max_descendant_size),
+        replace_by_fee: matches.is_present("replace_by_fee"),
+        replacement_fee_increment: parse_policy_value(&matches, "replacement_fee_increment", default_policy.replacement_fee_increment),
+        mempool_expiry_seconds: parse_policy_value(&matches, "mempool_expiry", default_policy.mempool_expiry_seconds),
+        max_pending_per_sender: parse_policy_value(&matches, "max_pending_per_sender", default_policy.max_pending_per_sender),
+        standard_scripts: match matches.value_of("standard_scripts") {
+            Some(kinds) => kinds.split(',').filter(|kind| !kind.is_empty()).map(|kind| kind.to_string()).collect(),
+            None => default_policy.standard_scripts,
+        },
+    };
 
-    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let mut mempool = Mempool::with_policy(policy);
+    mempool.set_clock(Arc::clone(&clock));
+    let mempool = Arc::new(Mutex::new(mempool));
+
+    // reload the saved state, and keep saving it
+    let persistence = matches.value_of("data_dir").map(|data_dir| {
+        let persistence = Persistence::new(std::path::Path::new(data_dir), &blockchain, &mempool)
+            .unwrap_or_else(|e| {
+                error!("Error creating data directory {}: {}", data_dir, e);
+                process::exit(1);
+            });
+        persistence.load();
+        persistence.clone().start();
+        persistence
+    });
 
     // start the worker
     let p2p_workers = matches
@@ -89,22 +242,42 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+
+    // start the signature verifier
+    let verifier_workers = matches
+        .value_of("verifier_workers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing verifier workers: {}", e);
+            process::exit(1);
+        });
+    if verifier_workers == 0 {
+        error!("At least one verifier worker is needed");
+        process::exit(1);
+    }
+    let (verifier_ctx, verifier) = verifier::new(verifier_workers);
+    verifier_ctx.start();
+
     let worker_ctx = worker::new(
         p2p_workers,
         msg_rx,
         &server,
         &blockchain,
         &mempool,
+        &verifier,
     );
     worker_ctx.start();
 
-    // start the miner
-    let (miner_ctx, miner) = miner::new(
-        &server,
-        &blockchain,
-        &mempool,
-    );
-    miner_ctx.start();
+    // the miner and the transaction generator draw from their own generators, derived from one
+    // seed so that a run can be reproduced
+    let mut rng = match matches.value_of("seed") {
+        Some(seed) => StdRng::seed_from_u64(seed.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing seed: {}", e);
+            process::exit(1);
+        })),
+        None => StdRng::from_entropy(),
+    };
 
     let account_index = matches
     .value_of("account_index")
@@ -117,15 +290,68 @@ fn main() {
     
     let private_key = [account_index; 32];
     let controlled_keypair = Ed25519KeyPair::from_seed_unchecked(&private_key).unwrap();
+    // mined blocks pay the controlled key unless told otherwise
+    let controlled_address = H160::from_pubkey(controlled_keypair.public_key().as_ref());
+
+    // start the miner
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    if miner_threads == 0 {
+        error!("At least one miner thread is needed");
+        process::exit(1);
+    }
+    let (miner_ctx, miner) = miner::new(
+        &server,
+        &blockchain,
+        &mempool,
+        miner_threads,
+        controlled_address,
+        StdRng::from_rng(&mut rng).unwrap(),
+        &clock,
+    );
+    miner_ctx.start();
 
     let transaction_generator = TransactionGenerator::new(
         &server,
         &mempool,
         &blockchain,
+        &verifier,
         controlled_keypair,
+        StdRng::from_rng(&mut rng).unwrap(),
+        &clock,
     );
     transaction_generator.start();
 
+    let channels = Arc::new(Mutex::new(ChannelManager::new(
+        &server,
+        &blockchain,
+        &mempool,
+        Ed25519KeyPair::from_seed_unchecked(&private_key).unwrap(),
+    )));
+
+    // hand out work to external hashing processes, possibly as a pool
+    let pool = matches.value_of("pool_share_factor").map(|share_factor| {
+        let parse = |name: &str, value: &str| value.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", name, e);
+            process::exit(1);
+        });
+        let window = matches.value_of("pool_window")
+            .map_or(DEFAULT_POOL_WINDOW, |window| parse("pool window", window) as usize);
+        let share_factor = parse("pool share factor", share_factor);
+        if share_factor == 0 {
+            error!("The pool share factor must be positive");
+            process::exit(1);
+        }
+        Pool::new(share_factor, window)
+    });
+    let work = Arc::new(Mutex::new(WorkManager::new(&server, &blockchain, &mempool, pool, controlled_address, &clock)));
+
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
@@ -165,6 +391,12 @@ fn main() {
         api_addr,
         &miner,
         &server,
+        &blockchain,
+        &mempool,
+        &verifier,
+        &channels,
+        &work,
+        &persistence,
     );
 
     loop {
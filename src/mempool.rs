@@ -1,46 +1,1104 @@
 use crate::transaction::SignedTransaction as Transaction;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use log::debug;
+use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
+use crate::address::H160;
+use crate::blockchain::Blockchain;
+use crate::clock::{Clock, SystemClock};
+use crate::policy::{Policy, PolicyViolation};
+use crate::transaction::{State, TransactionInput};
+use crate::persistence::write_atomically;
+use crate::fee_estimator::FeeEstimator;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
 
-/// Store all the received valid transactions which have not been included in the blockchain yet.
+/// How many evictions the mempool remembers
+const MAX_EVICTIONS: usize = 1_000;
+
+/// Why a transaction was not accepted into the mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// It fails the consensus checks against the tip of the longest chain
+    Invalid,
+    /// It breaks this node's policy
+    NonStandard(PolicyViolation),
+    /// The mempool is full of transactions paying a higher fee rate
+    MempoolFull,
+    /// It spends an output already spent by these mempool transactions
+    Conflict(Vec<H256>),
+    /// It conflicts with mempool transactions, but does not pay enough to replace them
+    ReplacementFeeTooLow { fee: u64, required: u64 },
+    /// Its signer already has the most pending transactions the policy allows
+    TooManyPending { sender: H160 },
+}
+
+impl RejectReason {
+    /// A short name of the kind of reason, for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RejectReason::Invalid => "invalid",
+            RejectReason::NonStandard(_) => "non-standard",
+            RejectReason::MempoolFull => "mempool-full",
+            RejectReason::Conflict(_) => "conflict",
+            RejectReason::ReplacementFeeTooLow { .. } => "replacement-fee-too-low",
+            RejectReason::TooManyPending { .. } => "too-many-pending",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RejectReason::Invalid => write!(f, "transaction check failed"),
+            RejectReason::NonStandard(violation) => write!(f, "non-standard: {}", violation),
+            RejectReason::MempoolFull => write!(f, "mempool full"),
+            RejectReason::Conflict(hashes) => write!(f, "conflicts with mempool transactions {:?}", hashes),
+            RejectReason::ReplacementFeeTooLow { fee, required } => {
+                write!(f, "replacement fee {} lower than the required {}", fee, required)
+            }
+            RejectReason::TooManyPending { sender } => write!(f, "too many pending transactions from {}", sender),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// Replaced by a conflicting transaction paying a higher fee, or a descendant of such a transaction
+    Replaced { by: H256 },
+    /// Paid too low a fee rate to stay in the full mempool
+    MempoolFull,
+    /// Stayed unconfirmed for longer than the expiry of the policy, or a descendant of such a transaction
+    Expired,
+}
+
+/// A transaction removed from the mempool without being confirmed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Eviction {
+    pub hash: H256,
+    pub reason: EvictionReason,
+}
+
+/// Counts of what happened to the transactions submitted to the mempool since the node started
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MempoolStats {
+    pub accepted: u64,
+    /// Rejected transactions by `RejectReason::kind`
+    pub rejected: HashMap<String, u64>,
+    pub replaced: u64,
+    /// Transactions evicted because the mempool was full
+    pub evicted: u64,
+    pub expired: u64,
+}
+
+/// A fee per serialized byte, compared exactly as a fraction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeRate {
+    pub fee: u64,
+    pub size: usize,
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        ((self.fee as u128) * (other.size as u128)).cmp(&((other.fee as u128) * (self.size as u128)))
+            .then(self.size.cmp(&other.size))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A transaction in the mempool, with the fee it pays
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub transaction: Transaction,
+    /// The valid signers of the transaction, kept to check it again without verifying its signatures
+    pub signers: Vec<H160>,
+    pub fee: u64,
+    pub size: usize,
+    /// The mempool transactions whose outputs this one spends
+    pub parents: HashSet<H256>,
+    /// The mempool transactions spending outputs of this one
+    pub children: HashSet<H256>,
+    /// When the transaction first entered the mempool, in milliseconds since the Unix epoch
+    pub arrival_ms: u128,
+}
+
+impl Entry {
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate { fee: self.fee, size: self.size }
+    }
+}
+
+/// Store all the received valid transactions which have not been included in the blockchain yet,
+/// ordered by fee rate. Once their total size exceeds the cap of the policy, the transactions
+/// paying the lowest fee rate are evicted.
+/// A transaction may spend outputs of other mempool transactions, forming unconfirmed chains.
 pub struct Mempool {
-    // TODO Optional: you may use other data structures if you wish.
-    hash_to_transaction: HashMap<H256, Transaction>,
+    hash_to_entry: HashMap<H256, Entry>,
+    /// All entries, by increasing fee rate
+    by_fee_rate: BTreeSet<(FeeRate, H256)>,
+    /// The eviction score of each entry (see `eviction_score`)
+    eviction_scores: HashMap<H256, FeeRate>,
+    /// All entries, by increasing eviction score
+    by_eviction_score: BTreeSet<(FeeRate, H256)>,
+    /// The entry spending each output spent by an entry
+    spent_by: HashMap<TransactionInput, H256>,
+    /// The hash of each entry by the hash of its raw transaction, which its outputs are referred by
+    raw_to_hash: HashMap<H256, H256>,
+    /// The total serialized size of all entries, in bytes
+    total_size: usize,
+    /// The latest evictions, oldest first
+    evictions: VecDeque<Eviction>,
+    /// All entries, by increasing arrival time
+    by_arrival: BTreeSet<(u128, H256)>,
+    /// The number of entries signed by each address
+    pending_by_sender: HashMap<H160, usize>,
+    stats: MempoolStats,
+    fee_estimator: FeeEstimator,
+    /// Incremented on every insertion and removal
+    revision: u64,
+    policy: Policy,
+    /// The source of arrival times, which transactions expire by
+    clock: Arc<dyn Clock>,
 }
 
 impl Mempool {
     pub fn new() -> Self {
+        Self::with_policy(Policy::default())
+    }
+
+    /// Create an empty mempool accepting transactions under `policy`
+    pub fn with_policy(policy: Policy) -> Self {
         Mempool {
-            hash_to_transaction: HashMap::new(),
+            hash_to_entry: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            eviction_scores: HashMap::new(),
+            by_eviction_score: BTreeSet::new(),
+            spent_by: HashMap::new(),
+            raw_to_hash: HashMap::new(),
+            total_size: 0,
+            evictions: VecDeque::new(),
+            by_arrival: BTreeSet::new(),
+            pending_by_sender: HashMap::new(),
+            stats: MempoolStats::default(),
+            fee_estimator: FeeEstimator::new(),
+            revision: 0,
+            policy,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Take arrival times from `clock` instead of the system clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Check a transaction against the tip of `blockchain` and this node's policy,
+    /// and insert it if it passes. Returns its hash.
+    pub fn accept(&mut self, transaction: Transaction, blockchain: &Blockchain) -> Result<H256, RejectReason> {
+        match transaction.verified_signers() {
+            Some(signers) => self.accept_verified(transaction, &signers, blockchain),
+            None => Err(RejectReason::Invalid),
+        }
+    }
+
+    /// Like `accept`, for a transaction whose valid signers are already known to be `signers`
+    pub fn accept_verified(&mut self, transaction: Transaction, signers: &[H160], blockchain: &Blockchain) -> Result<H256, RejectReason> {
+        self.expire();
+        let hash = transaction.hash();
+        // a transaction broadcast again is already counted:
+        if self.hash_to_entry.contains_key(&hash) {
+            return Ok(hash);
+        }
+        let result = self.admit(transaction, signers, blockchain);
+        match &result {
+            Ok(hash) => {
+                self.stats.accepted += 1;
+                let entry = &self.hash_to_entry[hash];
+                let fee_rate = entry.fee * 1000 / entry.size as u64;
+                self.fee_estimator.track(*hash, fee_rate, blockchain.get_height(&blockchain.tip()));
+            }
+            Err(reason) => *self.stats.rejected.entry(reason.kind().to_string()).or_insert(0) += 1,
+        }
+        result
+    }
+
+    /// The checks and insertion of `accept_verified`, for new and previously accepted transactions alike
+    fn admit(&mut self, transaction: Transaction, signers: &[H160], blockchain: &Blockchain) -> Result<H256, RejectReason> {
+        let hash = transaction.hash();
+        if self.hash_to_entry.contains_key(&hash) {
+            return Ok(hash);
+        }
+        let conflicts = self.get_conflicts(&transaction);
+        if !conflicts.is_empty() && !self.policy.replace_by_fee {
+            return Err(RejectReason::Conflict(conflicts));
+        }
+        // the conflicting transactions and their descendants, which would be replaced:
+        let replaced: HashSet<H256> = conflicts.iter()
+            .flat_map(|conflict| self.descendants(conflict).into_iter().chain(std::iter::once(*conflict)))
+            .collect();
+        if self.parents_of(&transaction).iter().any(|parent| replaced.contains(parent)) {
+            return Err(RejectReason::Invalid);
+        }
+        let sender = transaction.signer();
+        let replaced_pending = replaced.iter().filter(|hash| self.hash_to_entry[hash].transaction.signer() == sender).count();
+        let pending = self.pending_by_sender.get(&sender).copied().unwrap_or(0) - replaced_pending;
+        if pending >= self.policy.max_pending_per_sender {
+            return Err(RejectReason::TooManyPending { sender });
+        }
+        let inputs = self.input_state(&transaction, blockchain).ok_or(RejectReason::Invalid)?;
+        let height = blockchain.get_height(&blockchain.tip()) + 1;
+        if !Blockchain::spending_check(&transaction, signers, &inputs, height) {
+            return Err(RejectReason::Invalid);
+        }
+        let fee = Blockchain::transaction_fee(&transaction, &inputs);
+        self.policy.check(&transaction, fee).map_err(RejectReason::NonStandard)?;
+        self.check_chain_limits(&transaction).map_err(RejectReason::NonStandard)?;
+        if !conflicts.is_empty() {
+            let required = self.replacement_fee(transaction.size(), &conflicts, &replaced);
+            if fee < required {
+                return Err(RejectReason::ReplacementFeeTooLow { fee, required });
+            }
+        }
+        // remove the replaced transactions, parents first, keeping them in case the replacement
+        // does not fit:
+        let mut replaced: Vec<H256> = replaced.into_iter().collect();
+        replaced.sort_by_key(|hash| self.ancestors(hash).len());
+        let removed: Vec<Entry> = replaced.iter().filter_map(|hash| self.remove(hash)).collect();
+        if self.insert(transaction, signers.to_vec(), fee).contains(&hash) {
+            self.restore(removed);
+            return Err(RejectReason::MempoolFull);
+        }
+        for entry in removed {
+            self.record_eviction(entry.transaction.hash(), EvictionReason::Replaced { by: hash });
+        }
+        Ok(hash)
+    }
+
+    /// Put back entries removed for a replacement that did not fit, parents first. The mempool
+    /// fits them, as they were in it before, unless the replacement evicted their other parents.
+    fn restore(&mut self, entries: Vec<Entry>) {
+        for entry in entries {
+            let hash = entry.transaction.hash();
+            if !entry.parents.iter().all(|parent| self.hash_to_entry.contains_key(parent)) {
+                self.record_eviction(hash, EvictionReason::MempoolFull);
+                continue;
+            }
+            self.insert(entry.transaction, entry.signers, entry.fee);
+            self.set_arrival(&hash, entry.arrival_ms);
+        }
+    }
+
+    /// The outputs spent by `transaction`, taken from the state of the tip of `blockchain` or from
+    /// the mempool transactions it spends (or `None` if an input is in neither)
+    fn input_state(&self, transaction: &Transaction, blockchain: &Blockchain) -> Option<State> {
+        let tip_state = blockchain.get_state(&blockchain.tip());
+        transaction.raw.TransactionInput.iter().map(|input| {
+            let output = match tip_state.get(input) {
+                Some(output) => output.clone(),
+                None => {
+                    let parent = self.raw_to_hash.get(&input.prev_tx)?;
+                    self.hash_to_entry[parent].transaction.raw.TransactionOutput.get(input.txid as usize)?.clone()
+                }
+            };
+            Some((*input, output))
+        }).collect()
+    }
+
+    /// The smallest fee a transaction of `size` bytes must pay to replace `conflicts` and all
+    /// the `replaced` transactions depending on them. It pays for all of them, plus the replacement
+    /// increment of the policy for its own size, and beats the fee rate of each conflict by the increment.
+    fn replacement_fee(&self, size: usize, conflicts: &[H256], replaced: &HashSet<H256>) -> u64 {
+        let increment = self.policy.replacement_fee_increment as u128;
+        let size = size as u128;
+        let replaced_fee: u128 = replaced.iter().map(|hash| self.hash_to_entry[hash].fee as u128).sum();
+        let mut required = replaced_fee + (increment * size).div_ceil(1000).max(1);
+        for conflict in conflicts {
+            let entry = &self.hash_to_entry[conflict];
+            let (fee, conflict_size) = (entry.fee as u128, entry.size as u128);
+            required = required.max((size * (fee * 1000 + increment * conflict_size)).div_ceil(1000 * conflict_size));
+        }
+        u64::try_from(required).unwrap_or(u64::MAX)
+    }
+
+    /// Check that adding `transaction` keeps its unconfirmed chain within the limits of the policy
+    fn check_chain_limits(&self, transaction: &Transaction) -> Result<(), PolicyViolation> {
+        let size = transaction.size();
+        let ancestors = self.closure(self.parents_of(transaction), |entry| &entry.parents);
+        let count = ancestors.len() + 1;
+        if count > self.policy.max_ancestors {
+            return Err(PolicyViolation::TooManyAncestors { count });
+        }
+        let ancestor_size = size + ancestors.iter().map(|hash| self.hash_to_entry[hash].size).sum::<usize>();
+        if ancestor_size > self.policy.max_ancestor_size {
+            return Err(PolicyViolation::AncestorsTooLarge { size: ancestor_size });
+        }
+        for ancestor in &ancestors {
+            // the ancestor, its current descendants and the new transaction:
+            let descendants = self.descendants(ancestor);
+            let count = descendants.len() + 2;
+            if count > self.policy.max_descendants {
+                return Err(PolicyViolation::TooManyDescendants { count });
+            }
+            let descendant_size = size + self.hash_to_entry[ancestor].size
+                + descendants.iter().map(|hash| self.hash_to_entry[hash].size).sum::<usize>();
+            if descendant_size > self.policy.max_descendant_size {
+                return Err(PolicyViolation::DescendantsTooLarge { size: descendant_size });
+            }
+        }
+        Ok(())
+    }
+
+    /// The mempool transactions whose outputs `transaction` spends
+    fn parents_of(&self, transaction: &Transaction) -> HashSet<H256> {
+        transaction.raw.TransactionInput.iter()
+            .filter_map(|input| self.raw_to_hash.get(&input.prev_tx))
+            .copied()
+            .collect()
+    }
+
+    /// All the entries reachable from `start` by repeatedly following `next`
+    fn closure(&self, start: HashSet<H256>, next: fn(&Entry) -> &HashSet<H256>) -> HashSet<H256> {
+        let mut reached = HashSet::new();
+        let mut stack: Vec<H256> = start.into_iter().collect();
+        while let Some(hash) = stack.pop() {
+            if reached.insert(hash) {
+                stack.extend(next(&self.hash_to_entry[&hash]).iter().copied());
+            }
+        }
+        reached
+    }
+
+    /// The mempool transactions that `hash` spends outputs of, directly or indirectly
+    pub fn ancestors(&self, hash: &H256) -> HashSet<H256> {
+        match self.hash_to_entry.get(hash) {
+            Some(entry) => self.closure(entry.parents.clone(), |entry| &entry.parents),
+            None => HashSet::new(),
+        }
+    }
+
+    /// The mempool transactions spending outputs of `hash`, directly or indirectly
+    pub fn descendants(&self, hash: &H256) -> HashSet<H256> {
+        match self.hash_to_entry.get(hash) {
+            Some(entry) => self.closure(entry.children.clone(), |entry| &entry.children),
+            None => HashSet::new(),
         }
     }
 
     /// Get a transaction from the mempool by hash (or `None` if it does not exist)
     pub fn get_transaction(&self, hash: &H256) -> Option<&Transaction> {
-        self.hash_to_transaction.get(hash)
+        self.hash_to_entry.get(hash).map(|entry| &entry.transaction)
     }
 
-    /// Insert a transaction into the mempool
-    pub fn insert(&mut self, transaction: Transaction) {
-        // (Make sure you have implemented the `Hashable` trait for `SignedTransaction`, or there will be an error):
+    /// The state of the tip of `blockchain` after the mempool transactions, i.e. the outputs a new
+    /// transaction can spend without conflicting with the mempool
+    pub fn state(&self, blockchain: &Blockchain) -> State {
+        let mut state = blockchain.get_state(&blockchain.tip()).clone();
+        // all transactions, parents first:
+        for transaction in self.select_packages(self.len()) {
+            Blockchain::apply_transaction(&transaction, &mut state);
+        }
+        state
+    }
+
+    /// The mempool transactions, other than `transaction` itself, spending any of its inputs
+    pub fn get_conflicts(&self, transaction: &Transaction) -> Vec<H256> {
         let hash = transaction.hash();
-        self.hash_to_transaction.insert(hash, transaction);
+        let mut conflicts: Vec<H256> = transaction.raw.TransactionInput.iter()
+            .filter_map(|input| self.spent_by.get(input))
+            .filter(|spender| **spender != hash)
+            .copied()
+            .collect();
+        conflicts.sort();
+        conflicts.dedup();
+        conflicts
     }
 
-    /// Remove a random transaction from the mempool and return it (or `None` if it is empty)
+    pub fn get_entry(&self, hash: &H256) -> Option<&Entry> {
+        self.hash_to_entry.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hash_to_entry.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hash_to_entry.is_empty()
+    }
+
+    /// A number that changes whenever the contents of the mempool change
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// The total serialized size of the transactions in the mempool, in bytes
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// Iterate over the entries by decreasing fee rate
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.by_fee_rate.iter().rev().map(move |(_, hash)| &self.hash_to_entry[hash])
+    }
+
+    /// Select up to `max_transactions` transactions for a block, by ancestor score: repeatedly take
+    /// the transaction whose package (itself and its unselected ancestors) pays the highest fee rate,
+    /// with its whole package. A high-fee child thus pays for its low-fee parent. Parents come before
+    /// their children in the result. The mempool is left unchanged.
+    pub fn select_packages(&self, max_transactions: usize) -> Vec<Transaction> {
+        let mut ancestors: HashMap<H256, HashSet<H256>> = self.hash_to_entry.keys()
+            .map(|hash| (*hash, self.ancestors(hash)))
+            .collect();
+        // the number of ancestors in the mempool, which orders parents before their children:
+        let depth: HashMap<H256, usize> = ancestors.iter().map(|(hash, ancestors)| (*hash, ancestors.len())).collect();
+        let package_rate = |hash: &H256, ancestors: &HashSet<H256>| {
+            ancestors.iter().chain(std::iter::once(hash)).fold(FeeRate { fee: 0, size: 0 }, |rate, hash| {
+                let entry = &self.hash_to_entry[hash];
+                FeeRate { fee: rate.fee + entry.fee, size: rate.size + entry.size }
+            })
+        };
+        let mut scores: HashMap<H256, FeeRate> = ancestors.iter()
+            .map(|(hash, ancestors)| (*hash, package_rate(hash, ancestors)))
+            .collect();
+        let mut heap: BinaryHeap<(FeeRate, H256)> = scores.iter().map(|(hash, score)| (*score, *hash)).collect();
+
+        let mut selected: HashSet<H256> = HashSet::new();
+        let mut block: Vec<H256> = vec![];
+        while let Some((score, hash)) = heap.pop() {
+            // skip selected transactions and outdated scores:
+            if selected.contains(&hash) || scores[&hash] != score {
+                continue;
+            }
+            let mut package: Vec<H256> = ancestors[&hash].iter().copied().chain(std::iter::once(hash)).collect();
+            if block.len() + package.len() > max_transactions {
+                continue;
+            }
+            package.sort_by_key(|hash| depth[hash]);
+            for included in &package {
+                selected.insert(*included);
+                block.push(*included);
+            }
+            // the packages of the descendants no longer include the selected transactions:
+            for included in &package {
+                for descendant in self.descendants(included) {
+                    if selected.contains(&descendant) {
+                        continue;
+                    }
+                    let descendant_ancestors = ancestors.get_mut(&descendant).unwrap();
+                    descendant_ancestors.remove(included);
+                    let score = package_rate(&descendant, descendant_ancestors);
+                    scores.insert(descendant, score);
+                    heap.push((score, descendant));
+                }
+            }
+        }
+        block.iter().map(|hash| self.hash_to_entry[hash].transaction.clone()).collect()
+    }
+
+    /// Insert a transaction signed by `signers` and paying `fee` into the mempool, then evict the
+    /// transactions with the lowest eviction score, with their descendants, until the mempool fits
+    /// in its cap. Returns the hashes of the evicted transactions, which may include the inserted one.
+    pub fn insert(&mut self, transaction: Transaction, signers: Vec<H160>, fee: u64) -> Vec<H256> {
+        let hash = transaction.hash();
+        let raw_hash = transaction.raw.hash();
+        self.remove(&hash);
+        let parents = self.parents_of(&transaction);
+        let children: HashSet<H256> = (0..transaction.raw.TransactionOutput.len())
+            .filter_map(|txid| self.spent_by.get(&TransactionInput { txid: txid as u32, prev_tx: raw_hash }))
+            .copied()
+            .collect();
+        for parent in &parents {
+            self.hash_to_entry.get_mut(parent).unwrap().children.insert(hash);
+        }
+        for child in &children {
+            self.hash_to_entry.get_mut(child).unwrap().parents.insert(hash);
+        }
+        let arrival_ms = self.clock.now_ms();
+        let entry = Entry { size: transaction.size(), transaction, signers, fee, parents, children, arrival_ms };
+        self.total_size += entry.size;
+        self.by_fee_rate.insert((entry.fee_rate(), hash));
+        for input in &entry.transaction.raw.TransactionInput {
+            self.spent_by.insert(*input, hash);
+        }
+        self.raw_to_hash.insert(raw_hash, hash);
+        self.revision += 1;
+        self.by_arrival.insert((entry.arrival_ms, hash));
+        *self.pending_by_sender.entry(entry.transaction.signer()).or_insert(0) += 1;
+        self.hash_to_entry.insert(hash, entry);
+        // the packages of the ancestors now include this transaction:
+        let ancestors = self.ancestors(&hash);
+        self.update_eviction_scores(std::iter::once(hash).chain(ancestors));
+
+        let mut evicted = vec![];
+        while self.total_size > self.policy.max_mempool_size {
+            let &(_, lowest) = self.by_eviction_score.iter().next().unwrap();
+            evicted.extend(self.remove_with_descendants(&lowest).into_iter().map(|entry| entry.transaction.hash()));
+        }
+        for evicted in evicted.iter().filter(|evicted| **evicted != hash) {
+            self.record_eviction(*evicted, EvictionReason::MempoolFull);
+        }
+        evicted
+    }
+
+    /// The fee rate of a transaction, or that of it with its descendants if higher, which are
+    /// evicted together. A low-fee parent whose child pays for it thus stays as long as the child.
+    fn eviction_score(&self, hash: &H256) -> FeeRate {
+        let own = self.hash_to_entry[hash].fee_rate();
+        let package = self.descendants(hash).iter().fold(own, |rate, descendant| {
+            let entry = &self.hash_to_entry[descendant];
+            FeeRate { fee: rate.fee + entry.fee, size: rate.size + entry.size }
+        });
+        own.max(package)
+    }
+
+    /// Recompute the eviction scores of `hashes`, forgetting those no longer in the mempool
+    fn update_eviction_scores(&mut self, hashes: impl IntoIterator<Item = H256>) {
+        for hash in hashes {
+            if let Some(score) = self.eviction_scores.remove(&hash) {
+                self.by_eviction_score.remove(&(score, hash));
+            }
+            if self.hash_to_entry.contains_key(&hash) {
+                let score = self.eviction_score(&hash);
+                self.eviction_scores.insert(hash, score);
+                self.by_eviction_score.insert((score, hash));
+            }
+        }
+    }
+
+    fn record_eviction(&mut self, hash: H256, reason: EvictionReason) {
+        match reason {
+            EvictionReason::Replaced { .. } => self.stats.replaced += 1,
+            EvictionReason::MempoolFull => self.stats.evicted += 1,
+            EvictionReason::Expired => self.stats.expired += 1,
+        }
+        self.fee_estimator.forget(&hash);
+        self.evictions.push_back(Eviction { hash, reason });
+        while self.evictions.len() > MAX_EVICTIONS {
+            self.evictions.pop_front();
+        }
+    }
+
+    pub fn stats(&self) -> &MempoolStats {
+        &self.stats
+    }
+
+    pub fn fee_estimator(&self) -> &FeeEstimator {
+        &self.fee_estimator
+    }
+
+    /// Replace the fee estimator, e.g. with one saved by a previous run
+    pub fn set_fee_estimator(&mut self, fee_estimator: FeeEstimator) {
+        self.fee_estimator = fee_estimator;
+    }
+
+    /// Remove the transactions that arrived longer ago than the expiry of the policy, with their
+    /// descendants. Returns how many were removed.
+    pub fn expire(&mut self) -> usize {
+        let now_ms = self.clock.now_ms();
+        let cutoff_ms = now_ms.saturating_sub(self.policy.mempool_expiry_seconds as u128 * 1000);
+        let mut expired = 0;
+        while let Some(&(arrival_ms, hash)) = self.by_arrival.iter().next() {
+            if arrival_ms >= cutoff_ms {
+                break;
+            }
+            for entry in self.remove_with_descendants(&hash) {
+                self.record_eviction(entry.transaction.hash(), EvictionReason::Expired);
+                expired += 1;
+            }
+        }
+        expired
+    }
+
+    /// The latest transactions evicted from the mempool, oldest first
+    pub fn evictions(&self) -> impl Iterator<Item = &Eviction> {
+        self.evictions.iter()
+    }
+
+    /// Remove the transaction paying the highest fee rate among those not spending outputs of
+    /// other mempool transactions, and return it (or `None` if the mempool is empty). Successive
+    /// calls thus return parents before their children.
     pub fn pop(&mut self) -> Option<Transaction> {
-        let hash = self.hash_to_transaction.keys().next().cloned();
-        if let Some(hash) = hash {
-            self.hash_to_transaction.remove(&hash)
-        } else {
-            None
+        let hash = self.by_fee_rate.iter().rev()
+            .map(|(_, hash)| *hash)
+            .find(|hash| self.hash_to_entry[hash].parents.is_empty())?;
+        self.remove(&hash).map(|entry| entry.transaction)
+    }
+
+    /// Remove a transaction from the mempool by hash, returning its entry if it was there.
+    /// Its descendants stay in the mempool, e.g. for when it is confirmed.
+    pub fn remove(&mut self, hash: &H256) -> Option<Entry> {
+        let ancestors = self.ancestors(hash);
+        let entry = self.hash_to_entry.remove(hash)?;
+        self.by_fee_rate.remove(&(entry.fee_rate(), *hash));
+        for input in &entry.transaction.raw.TransactionInput {
+            if self.spent_by.get(input) == Some(hash) {
+                self.spent_by.remove(input);
+            }
+        }
+        for parent in &entry.parents {
+            self.hash_to_entry.get_mut(parent).unwrap().children.remove(hash);
         }
+        for child in &entry.children {
+            self.hash_to_entry.get_mut(child).unwrap().parents.remove(hash);
+        }
+        self.raw_to_hash.remove(&entry.transaction.raw.hash());
+        self.by_arrival.remove(&(entry.arrival_ms, *hash));
+        self.revision += 1;
+        let sender = entry.transaction.signer();
+        let pending = self.pending_by_sender.get_mut(&sender).unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.pending_by_sender.remove(&sender);
+        }
+        self.total_size -= entry.size;
+        // the packages of the ancestors no longer include this transaction:
+        self.update_eviction_scores(std::iter::once(*hash).chain(ancestors));
+        Some(entry)
+    }
+
+    /// Remove a transaction and all its descendants, which can no longer be valid without it
+    pub fn remove_with_descendants(&mut self, hash: &H256) -> Vec<Entry> {
+        let descendants = self.descendants(hash);
+        std::iter::once(*hash).chain(descendants)
+            .filter_map(|hash| self.remove(&hash))
+            .collect()
     }
 
     pub fn remove_transaction(&mut self, transaction: Transaction) {
-        let hash = transaction.hash();
-        self.hash_to_transaction.remove(&hash);
+        self.remove(&transaction.hash());
+    }
+
+    /// Bring the mempool up to date after the tip of `blockchain` moved from `old_tip`: drop the
+    /// transactions confirmed by the newly connected blocks, put back the transactions of the
+    /// blocks disconnected by a reorg, and check all of them against the new tip, dropping those
+    /// that became invalid (e.g. because they conflict with a confirmed transaction).
+    pub fn update_tip(&mut self, old_tip: &H256, blockchain: &Blockchain) {
+        // expire here too, so that a node receiving no transactions does not keep mining old ones:
+        self.expire();
+        let (disconnected, connected) = blockchain.route(old_tip, &blockchain.tip());
+        for hash in &connected {
+            let transactions = &blockchain.get_block(hash).content.transactions;
+            self.fee_estimator.process_block(blockchain.get_height(hash), transactions.iter().map(|tx| tx.hash()));
+        }
+        let confirmed: HashSet<H256> = connected.iter()
+            .flat_map(|hash| blockchain.get_block(hash).content.transactions.iter())
+            .map(|tx| tx.hash())
+            .collect();
+        // the transactions of the disconnected blocks, oldest first and without their coinbase:
+        let mut candidates: Vec<(Transaction, Option<Vec<H160>>, Option<u128>)> = disconnected.iter().rev()
+            .flat_map(|hash| blockchain.get_block(hash).content.transactions.iter().skip(1))
+            .map(|tx| (tx.clone(), None, None))
+            .collect();
+        // then the current entries, parents before children:
+        candidates.extend(self.topological_entries().into_iter()
+            .map(|entry| (entry.transaction.clone(), Some(entry.signers.clone()), Some(entry.arrival_ms))));
+        self.hash_to_entry.clear();
+        self.by_fee_rate.clear();
+        self.eviction_scores.clear();
+        self.by_eviction_score.clear();
+        self.spent_by.clear();
+        self.raw_to_hash.clear();
+        self.by_arrival.clear();
+        self.pending_by_sender.clear();
+        self.total_size = 0;
+        self.revision += 1;
+
+        for (tx, signers, arrival_ms) in candidates {
+            let hash = tx.hash();
+            if confirmed.contains(&hash) {
+                continue;
+            }
+            if let Err(reason) = self.readmit(tx, signers, arrival_ms, blockchain) {
+                debug!("Dropped transaction {} after tip change: {}", hash, reason);
+                self.fee_estimator.forget(&hash);
+            }
+        }
+    }
+
+    /// The entries, parents before children
+    fn topological_entries(&self) -> Vec<&Entry> {
+        let mut entries: Vec<(usize, &Entry)> = self.iter()
+            .map(|entry| (self.ancestors(&entry.transaction.hash()).len(), entry))
+            .collect();
+        entries.sort_by_key(|(ancestors, _)| *ancestors);
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// Accept a transaction that was in the mempool before (e.g. before a reorg or a restart),
+    /// keeping its arrival time if known. Its signatures are verified unless its `signers` are known.
+    fn readmit(
+        &mut self,
+        transaction: Transaction,
+        signers: Option<Vec<H160>>,
+        arrival_ms: Option<u128>,
+        blockchain: &Blockchain,
+    ) -> Result<H256, RejectReason> {
+        let signers = signers.or_else(|| transaction.verified_signers()).ok_or(RejectReason::Invalid)?;
+        let hash = self.admit(transaction, &signers, blockchain)?;
+        if let Some(arrival_ms) = arrival_ms {
+            self.set_arrival(&hash, arrival_ms);
+        }
+        Ok(hash)
+    }
+
+    fn set_arrival(&mut self, hash: &H256, arrival_ms: u128) {
+        let entry = self.hash_to_entry.get_mut(hash).unwrap();
+        self.by_arrival.remove(&(entry.arrival_ms, *hash));
+        self.by_arrival.insert((arrival_ms, *hash));
+        entry.arrival_ms = arrival_ms;
+    }
+
+    /// Save the transactions and their arrival times to `path`
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        let saved: Vec<(&Transaction, u128)> = self.topological_entries().into_iter()
+            .map(|entry| (&entry.transaction, entry.arrival_ms))
+            .collect();
+        let data = bincode::serialize(&saved).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomically(path, &data)
+    }
+
+    /// Load the transactions saved to `path` by `dump`, accepting those still valid at the tip of
+    /// `blockchain`. Returns how many were accepted.
+    pub fn load(&mut self, path: &Path, blockchain: &Blockchain) -> io::Result<usize> {
+        let data = std::fs::read(path)?;
+        let saved: Vec<(Transaction, u128)> = bincode::deserialize(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut accepted = 0;
+        for (tx, arrival_ms) in saved {
+            let hash = tx.hash();
+            match self.readmit(tx, None, Some(arrival_ms), blockchain) {
+                Ok(_) => accepted += 1,
+                Err(reason) => debug!("Dropped saved transaction {}: {}", hash, reason),
+            }
+        }
+        Ok(accepted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::block_template::BlockTemplate;
+    use crate::blockchain::ICO_VALUE;
+    use crate::chain_spec::ChainSpec;
+    use crate::clock::ManualClock;
+    use crate::crypto::key_pair;
+    use crate::policy::DEFAULT_MEMPOOL_EXPIRY_SECONDS;
+    use crate::transaction::{Script, Transaction as RawTransaction, TransactionInput, TransactionOutput};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn transaction(value: u64) -> Transaction {
+        let output = TransactionOutput { recipient: H160::default(), value, script: Script::PayToAddress };
+        let raw = RawTransaction { TransactionInput: vec![], TransactionOutput: vec![output] };
+        Transaction::from_raw(raw, &key_pair::random())
+    }
+
+    #[test]
+    fn fee_rate_order_and_eviction() {
+        let size = transaction(0).size();
+        let mut mempool = Mempool::with_policy(Policy { max_mempool_size: 3 * size, ..Policy::default() });
+        let low = transaction(1);
+        let middle = transaction(2);
+        let high = transaction(3);
+        assert!(mempool.insert(middle.clone(), vec![], 200).is_empty());
+        assert!(mempool.insert(high.clone(), vec![], 300).is_empty());
+        assert!(mempool.insert(low.clone(), vec![], 100).is_empty());
+        let order: Vec<H256> = mempool.iter().map(|entry| entry.transaction.hash()).collect();
+        assert_eq!(order, vec![high.hash(), middle.hash(), low.hash()]);
+
+        // a fourth transaction evicts the lowest fee rate, possibly itself:
+        let higher = transaction(4);
+        assert_eq!(mempool.insert(higher.clone(), vec![], 400), vec![low.hash()]);
+        let lowest = transaction(5);
+        assert_eq!(mempool.insert(lowest.clone(), vec![], 50), vec![lowest.hash()]);
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.total_size(), 3 * size);
+
+        assert_eq!(mempool.pop().unwrap().hash(), higher.hash());
+        assert_eq!(mempool.pop().unwrap().hash(), high.hash());
+    }
+
+    fn ico_key() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap()
+    }
+
+    /// Spend `input`, worth `value` and owned by `ico_key`, back to the same key, paying `fee`
+    fn spend(input: TransactionInput, value: u64, fee: u64) -> Transaction {
+        let key = ico_key();
+        let recipient = H160::from_pubkey(key.public_key().as_ref());
+        let raw = RawTransaction {
+            TransactionInput: vec![input],
+            TransactionOutput: vec![TransactionOutput { recipient, value: value - fee, script: Script::PayToAddress }],
+        };
+        Transaction::from_raw(raw, &key)
+    }
+
+    /// Spend an output of the initial coin offering, paying `fee`
+    fn ico_spend(fee: u64) -> Transaction {
+        spend(TransactionInput { txid: 3, prev_tx: ChainSpec::default().id() }, ICO_VALUE, fee)
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::with_policy(Policy { replace_by_fee: true, ..Policy::default() });
+        let original = ico_spend(1_000);
+        let child = spend(TransactionInput { txid: 0, prev_tx: original.raw.hash() }, ICO_VALUE - 1_000, 1_000);
+        let original_hash = mempool.accept(original, &blockchain).unwrap();
+        let child_hash = mempool.accept(child, &blockchain).unwrap();
+
+        // paying for both replaced transactions is not enough without the increment:
+        let result = mempool.accept(ico_spend(2_000), &blockchain);
+        assert!(matches!(result, Err(RejectReason::ReplacementFeeTooLow { fee: 2_000, .. })));
+        let replacement = mempool.accept(ico_spend(3_000), &blockchain).unwrap();
+        assert_eq!(mempool.len(), 1);
+        let evicted: HashSet<H256> = mempool.evictions()
+            .filter(|eviction| eviction.reason == EvictionReason::Replaced { by: replacement })
+            .map(|eviction| eviction.hash)
+            .collect();
+        assert_eq!(evicted, [original_hash, child_hash].iter().copied().collect());
+    }
+
+    #[test]
+    fn template_leaves_transactions() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let hash = mempool.accept(ico_spend(1_000), &blockchain).unwrap();
+        let payouts = [(H160::default(), 1)];
+        let template = BlockTemplate::assemble(&blockchain, &mempool, &payouts, 0);
+        assert_eq!(template.content.transactions.len(), 2);
+        // abandoning the template loses nothing:
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(BlockTemplate::assemble(&blockchain, &mempool, &payouts, 0).content.transactions.len(), 2);
+
+        let block = template.block(0);
+        assert!(Blockchain::merkle_check(&block));
+        let old_tip = blockchain.tip();
+        let revision = mempool.revision();
+        blockchain.insert(&block);
+        mempool.update_tip(&old_tip, &blockchain);
+        assert!(mempool.get_transaction(&hash).is_none());
+        assert!(mempool.is_empty());
+        assert!(mempool.revision() > revision);
+    }
+
+    #[test]
+    fn replacement_in_full_mempool() {
+        let blockchain = Blockchain::new();
+        let original = ico_spend(1_000);
+        let other = spend(TransactionInput { txid: 6, prev_tx: ChainSpec::default().id() }, ICO_VALUE, 50_000);
+        let policy = Policy { replace_by_fee: true, max_mempool_size: original.size() + other.size(), ..Policy::default() };
+        let mut mempool = Mempool::with_policy(policy);
+        let original_hash = mempool.accept(original.clone(), &blockchain).unwrap();
+        let other_hash = mempool.accept(other, &blockchain).unwrap();
+
+        // a larger replacement paying a lower fee rate than the other transaction does not fit:
+        let key = ico_key();
+        let recipient = H160::from_pubkey(key.public_key().as_ref());
+        let output = TransactionOutput { recipient, value: (ICO_VALUE - 5_000) / 2, script: Script::PayToAddress };
+        let raw = RawTransaction { TransactionInput: original.raw.TransactionInput.clone(), TransactionOutput: vec![output.clone(), output] };
+        let replacement = Transaction::from_raw(raw, &key);
+        assert_eq!(mempool.accept(replacement.clone(), &blockchain), Err(RejectReason::MempoolFull));
+        // and the transaction it would have replaced stays:
+        assert!(mempool.get_transaction(&original_hash).is_some());
+        assert!(mempool.get_transaction(&other_hash).is_some());
+        assert!(mempool.get_transaction(&replacement.hash()).is_none());
+        assert_eq!(mempool.stats().replaced, 0);
+        assert_eq!(mempool.total_size(), original.size() + mempool.get_entry(&other_hash).unwrap().size);
+    }
+
+    #[test]
+    fn eviction_by_package() {
+        let blockchain = Blockchain::new();
+        let parent = ico_spend(100);
+        let child = spend(TransactionInput { txid: 0, prev_tx: parent.raw.hash() }, ICO_VALUE - 100, 10_000);
+        let other = spend(TransactionInput { txid: 6, prev_tx: ChainSpec::default().id() }, ICO_VALUE, 2_000);
+        let newer = spend(TransactionInput { txid: 9, prev_tx: ChainSpec::default().id() }, ICO_VALUE, 3_000);
+        let mut mempool = Mempool::with_policy(Policy { max_mempool_size: 3 * parent.size(), ..Policy::default() });
+        let parent_hash = mempool.accept(parent, &blockchain).unwrap();
+        let child_hash = mempool.accept(child, &blockchain).unwrap();
+        let other_hash = mempool.accept(other, &blockchain).unwrap();
+        // the low-fee parent is paid for by its child, so the other transaction goes first:
+        mempool.accept(newer, &blockchain).unwrap();
+        assert!(mempool.get_transaction(&other_hash).is_none());
+        assert!(mempool.get_transaction(&parent_hash).is_some());
+        assert!(mempool.get_transaction(&child_hash).is_some());
+        // without its child, the parent is scored by its own fee rate again:
+        mempool.remove(&child_hash);
+        let parent_rate = mempool.get_entry(&parent_hash).unwrap().fee_rate();
+        assert_eq!(mempool.by_eviction_score.iter().next(), Some(&(parent_rate, parent_hash)));
+        assert_eq!(mempool.by_eviction_score.len(), mempool.len());
+    }
+
+    #[test]
+    fn dump_and_load() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let parent = ico_spend(1_000);
+        let child = spend(TransactionInput { txid: 0, prev_tx: parent.raw.hash() }, ICO_VALUE - 1_000, 1_000);
+        let parent_hash = mempool.accept(parent, &blockchain).unwrap();
+        let child_hash = mempool.accept(child, &blockchain).unwrap();
+        mempool.set_arrival(&parent_hash, 42);
+
+        let path = std::env::temp_dir().join(format!("mempool-{}.dat", parent_hash));
+        mempool.dump(&path).unwrap();
+        let mut reloaded = Mempool::new();
+        assert_eq!(reloaded.load(&path, &blockchain).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.get_entry(&parent_hash).unwrap().arrival_ms, 42);
+        assert!(reloaded.get_entry(&child_hash).unwrap().parents.contains(&parent_hash));
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let parent = ico_spend(100);
+        let child = spend(TransactionInput { txid: 0, prev_tx: parent.raw.hash() }, ICO_VALUE - 100, 10_000);
+        let other = spend(TransactionInput { txid: 6, prev_tx: ChainSpec::default().id() }, ICO_VALUE, 2_000);
+        let parent_hash = mempool.accept(parent, &blockchain).unwrap();
+        let child_hash = mempool.accept(child, &blockchain).unwrap();
+        let other_hash = mempool.accept(other, &blockchain).unwrap();
+
+        let hashes = |transactions: Vec<Transaction>| transactions.iter().map(|tx| tx.hash()).collect::<Vec<_>>();
+        assert_eq!(hashes(mempool.select_packages(3)), vec![parent_hash, child_hash, other_hash]);
+        // the package of the child does not fit:
+        assert_eq!(hashes(mempool.select_packages(1)), vec![other_hash]);
+        assert_eq!(mempool.len(), 3);
     }
-        
-    // TODO Optional: you may want to add more methods here...
-}
\ No newline at end of file
+
+    #[test]
+    fn expiry_and_sender_limit() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::with_policy(Policy { max_pending_per_sender: 1, ..Policy::default() });
+        let clock = Arc::new(ManualClock::new(2 * DEFAULT_MEMPOOL_EXPIRY_SECONDS * 1000));
+        mempool.set_clock(clock.clone());
+        let hash = mempool.accept(ico_spend(1_000), &blockchain).unwrap();
+        let other = spend(TransactionInput { txid: 6, prev_tx: ChainSpec::default().id() }, ICO_VALUE, 1_000);
+        let sender = other.signer();
+        assert_eq!(mempool.accept(other.clone(), &blockchain), Err(RejectReason::TooManyPending { sender }));
+        // a re-broadcast is not counted again:
+        assert_eq!(mempool.accept(ico_spend(1_000), &blockchain), Ok(hash));
+        assert_eq!(mempool.stats().accepted, 1);
+        assert_eq!(mempool.stats().rejected["too-many-pending"], 1);
+
+        // a tip change expires old transactions, even if none arrive:
+        mempool.set_arrival(&hash, 0);
+        mempool.update_tip(&blockchain.tip(), &blockchain);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.stats().expired, 1);
+        assert_eq!(mempool.evictions().last(), Some(&Eviction { hash, reason: EvictionReason::Expired }));
+
+        let other_hash = mempool.accept(other, &blockchain).unwrap();
+        clock.advance(DEFAULT_MEMPOOL_EXPIRY_SECONDS * 1000);
+        assert_eq!(mempool.expire(), 0);
+        clock.advance(1);
+        assert_eq!(mempool.expire(), 1);
+        assert!(mempool.get_transaction(&other_hash).is_none());
+    }
+
+    #[test]
+    fn chained_transactions() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::with_policy(Policy { max_ancestors: 2, ..Policy::default() });
+        let parent = ico_spend(1_000);
+        let child = spend(TransactionInput { txid: 0, prev_tx: parent.raw.hash() }, ICO_VALUE - 1_000, 5_000);
+        let grandchild = spend(TransactionInput { txid: 0, prev_tx: child.raw.hash() }, ICO_VALUE - 6_000, 1_000);
+        assert_eq!(mempool.accept(child.clone(), &blockchain), Err(RejectReason::Invalid));
+        let parent_hash = mempool.accept(parent, &blockchain).unwrap();
+        let child_hash = mempool.accept(child, &blockchain).unwrap();
+        assert_eq!(mempool.ancestors(&child_hash), [parent_hash].iter().copied().collect());
+        assert_eq!(mempool.descendants(&parent_hash), [child_hash].iter().copied().collect());
+        assert_eq!(mempool.accept(grandchild, &blockchain),
+                   Err(RejectReason::NonStandard(PolicyViolation::TooManyAncestors { count: 3 })));
+
+        // the child pays a higher fee rate, but comes after its parent:
+        assert_eq!(mempool.pop().unwrap().hash(), parent_hash);
+        assert_eq!(mempool.pop().unwrap().hash(), child_hash);
+    }
+
+    #[test]
+    fn conflicting_spends() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let hash = mempool.accept(ico_spend(1_000), &blockchain).unwrap();
+        let double_spend = ico_spend(2_000);
+        assert_eq!(mempool.get_conflicts(&double_spend), vec![hash]);
+        assert_eq!(mempool.accept(double_spend.clone(), &blockchain), Err(RejectReason::Conflict(vec![hash])));
+        mempool.remove(&hash);
+        assert!(mempool.get_conflicts(&double_spend).is_empty());
+        assert!(mempool.accept(double_spend, &blockchain).is_ok());
+    }
+
+    #[test]
+    fn state_after_mempool() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let parent = ico_spend(1_000);
+        let parent_output = TransactionInput { txid: 0, prev_tx: parent.raw.hash() };
+        let child = spend(parent_output, ICO_VALUE - 1_000, 1_000);
+        let child_output = TransactionInput { txid: 0, prev_tx: child.raw.hash() };
+        let ico_output = parent.raw.TransactionInput[0];
+        mempool.accept(parent, &blockchain).unwrap();
+        mempool.accept(child, &blockchain).unwrap();
+        let state = mempool.state(&blockchain);
+        assert!(!state.contains_key(&ico_output));
+        assert!(!state.contains_key(&parent_output));
+        assert_eq!(state[&child_output].value, ICO_VALUE - 2_000);
+        assert_eq!(state.len(), blockchain.get_state(&blockchain.tip()).len());
+    }
+
+    #[test]
+    fn reorg_resurrects_transactions() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut mempool = Mempool::new();
+        let tx = ico_spend(1_000);
+        let hash = mempool.accept(tx.clone(), &blockchain).unwrap();
+
+        let mut block = generate_random_block(&genesis_hash);
+        block.content.transactions.push(tx);
+        blockchain.insert(&block);
+        mempool.update_tip(&genesis_hash, &blockchain);
+        assert!(mempool.is_empty());
+
+        let fork_1 = generate_random_block(&genesis_hash);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_1);
+        blockchain.insert(&fork_2);
+        mempool.update_tip(&block.hash(), &blockchain);
+        assert!(mempool.get_transaction(&hash).is_some());
+    }
+
+    #[test]
+    fn stale_templates() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let payouts = [(H160::default(), 1)];
+        let template = BlockTemplate::assemble(&blockchain, &mempool, &payouts, 0);
+        assert!(!template.is_stale(&blockchain, &mempool));
+        // a new transaction makes the template stale:
+        mempool.accept(ico_spend(1_000), &blockchain).unwrap();
+        assert!(template.is_stale(&blockchain, &mempool));
+        let template = BlockTemplate::assemble(&blockchain, &mempool, &payouts, 0);
+        assert_eq!(template.content.transactions.len(), 2);
+        assert!(!template.is_stale(&blockchain, &mempool));
+        // and so does a new tip:
+        let old_tip = blockchain.tip();
+        blockchain.insert(&generate_random_block(&old_tip));
+        mempool.update_tip(&old_tip, &blockchain);
+        assert!(template.is_stale(&blockchain, &mempool));
+    }
+}
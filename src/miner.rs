@@ -1,43 +1,127 @@
 use crate::network::server::Handle as ServerHandle;
 
 use log::info;
+use serde::{Serialize, Deserialize};
+use rand::distributions::Exp;
+use rand::rngs::StdRng;
+use rand::Rng;
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{select, unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 
 use std::thread;
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use crate::address::H160;
+use crate::block::Block;
 use crate::blockchain::Blockchain;
 use crate::mempool::Mempool;
-use crate::transaction::SignedTransaction as Transaction;
-use crate::crypto::merkle::MerkleTree;
-use crate::block::{Block, Header, Content};
-use crate::crypto::hash::Hashable;
+use crate::block_template::BlockTemplate;
+use crate::clock::Clock;
+use crate::crypto::hash::{H256, Hashable};
 use crate::network::message::Message;
-use crate::blockchain::BlockOrigin;
+use crate::blockchain::{BlockOrigin, TipChange};
+
+/// How often the miner checks whether its block template is stale
+const TEMPLATE_CHECK_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// How long an idle hashing thread waits before checking for work again
+const IDLE_INTERVAL: time::Duration = time::Duration::from_millis(10);
+/// How often the miner logs the hash rate of each thread
+const HASH_RATE_REPORT_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// The size of the nonce space split among the hashing threads
+const NONCE_SPACE: u64 = 1 << 32;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Simulate(f64), // the number is the expected blocks per second
+    Pause,
+    Resume,
+    Config { payout_address: Option<H160>, threads: Option<usize> },
+    Status(Sender<MinerStatus>),
     Exit,
 }
 
+#[derive(Clone, Copy)]
 enum OperatingState {
     Paused,
     Run(u64),
+    /// Simulated proof of work for regtest chains, finding blocks at the given rate per second
+    Simulate(f64),
     ShutDown,
 }
 
+/// The work shared between the miner thread and the hashing threads
+struct Work {
+    /// The block being mined, or `None` while the miner is not running
+    template: RwLock<Option<Arc<BlockTemplate>>>,
+    /// Bumped whenever `template` is replaced, so that hashing threads drop stale work
+    generation: AtomicU64,
+    /// The lambda of the miner, in microseconds to sleep between nonces
+    lambda: AtomicU64,
+    /// The number of hashing threads searching, the others being idle
+    active_threads: AtomicUsize,
+    /// The number of hashes computed by each hashing thread ever spawned
+    hash_counts: RwLock<Vec<Arc<AtomicU64>>>,
+}
+
+/// What the miner is doing and how well, for the API
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MinerStatus {
+    /// "paused", "running" or "simulating"
+    pub state: String,
+    pub lambda: Option<u64>,
+    /// The simulated blocks per second
+    pub rate: Option<f64>,
+    pub threads: usize,
+    pub payout_address: H160,
+    /// The hashes per second of each thread since the last report
+    pub hash_rates: Vec<f64>,
+    pub blocks_mined: u64,
+    /// Mined blocks that are not in the longest chain
+    pub stale_blocks: usize,
+    /// How long templates were mined on after the tip had moved on
+    pub stale_work_ms: u128,
+    /// Seconds since the miner first started
+    pub uptime_seconds: f64,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
+    /// What to resume when paused with `ControlSignal::Pause`
+    paused_state: Option<OperatingState>,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    num_threads: usize,
+    /// Who the coinbase of mined blocks pays
+    payout_address: H160,
+    /// The sources of randomness (for simulated mining) and of block timestamps
+    rng: StdRng,
+    clock: Arc<dyn Clock>,
+    work: Arc<Work>,
+    /// Channel for receiving the blocks found by the hashing threads
+    solution_chan: Receiver<Block>,
+    solution_sender: Sender<Block>,
+    /// Channel for learning that the tip changed, so that the template is stale
+    tip_chan: Receiver<TipChange>,
+    /// The block being mined
+    template: Option<Arc<BlockTemplate>>,
+    last_template_check: Instant,
+    /// When the next simulated block is found, in milliseconds since the Unix epoch
+    next_block_at_ms: Option<u128>,
+    last_report: Instant,
+    last_hash_counts: Vec<u64>,
     // For experiments:
     total_blocks_mined: u64,
-    start_time: Option<SystemTime>,
+    mined_blocks: Vec<H256>,
+    /// How long templates were mined on after the tip had moved on, in milliseconds, and how many
+    stale_work_ms: u128,
+    stale_templates: u64,
+    /// When the miner first started, in milliseconds since the Unix epoch
+    start_ms: Option<u128>,
 }
 
 #[derive(Clone)]
@@ -50,17 +134,45 @@ pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    num_threads: usize,
+    payout_address: H160,
+    rng: StdRng,
+    clock: &Arc<dyn Clock>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let (solution_sender, solution_receiver) = unbounded();
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
+        paused_state: None,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        num_threads,
+        payout_address,
+        rng,
+        clock: Arc::clone(clock),
+        work: Arc::new(Work {
+            template: RwLock::new(None),
+            generation: AtomicU64::new(0),
+            lambda: AtomicU64::new(0),
+            active_threads: AtomicUsize::new(num_threads),
+            hash_counts: RwLock::new(vec![]),
+        }),
+        solution_chan: solution_receiver,
+        solution_sender,
+        tip_chan: blockchain.lock().unwrap().subscribe_tip(),
+        template: None,
+        last_template_check: Instant::now(),
+        next_block_at_ms: None,
+        last_report: Instant::now(),
+        last_hash_counts: vec![],
         total_blocks_mined: 0,
-        start_time: None,
+        mined_blocks: vec![],
+        stale_work_ms: 0,
+        stale_templates: 0,
+        start_ms: None,
     };
 
     let handle = Handle {
@@ -81,31 +193,231 @@ impl Handle {
             .unwrap();
     }
 
+    /// Simulate mining on a regtest chain, finding `rate` blocks per second on average
+    pub fn simulate(&self, rate: f64) {
+        self.control_chan
+            .send(ControlSignal::Simulate(rate))
+            .unwrap();
+    }
+
+    /// Stop mining until `resume`, keeping the lambda or simulated rate
+    pub fn pause(&self) -> Result<(), String> {
+        self.send(ControlSignal::Pause)
+    }
+
+    pub fn resume(&self) -> Result<(), String> {
+        self.send(ControlSignal::Resume)
+    }
+
+    /// Change who mined blocks pay and how many threads search for nonces
+    pub fn config(&self, payout_address: Option<H160>, threads: Option<usize>) -> Result<(), String> {
+        self.send(ControlSignal::Config { payout_address, threads })
+    }
+
+    fn send(&self, signal: ControlSignal) -> Result<(), String> {
+        self.control_chan.send(signal).map_err(|_| "miner has shut down".to_string())
+    }
+
+    /// The status of the miner, or `None` if it has shut down
+    pub fn status(&self) -> Option<MinerStatus> {
+        let (sender, receiver) = unbounded();
+        self.control_chan.send(ControlSignal::Status(sender)).ok()?;
+        receiver.recv().ok()
+    }
+
+}
+
+/// The range of nonces searched by hashing thread `index` of `num_threads`: the nonce space is
+/// split evenly, the last thread taking the remainder
+fn nonce_range(index: usize, num_threads: usize) -> (u64, u64) {
+    let range_size = NONCE_SPACE / num_threads as u64;
+    let first = index as u64 * range_size;
+    let end = if index + 1 == num_threads { NONCE_SPACE } else { first + range_size };
+    (first, end)
+}
+
+/// Search the nonces in the range of hashing thread `index` of the current template, then the
+/// same range again with the timestamp refreshed from `clock`, until a block is found or the
+/// template is replaced
+fn hashing_loop(work: &Work, index: usize, hash_count: &AtomicU64, solution_chan: &Sender<Block>, clock: &dyn Clock) {
+    loop {
+        let generation = work.generation.load(Ordering::Acquire);
+        let num_threads = work.active_threads.load(Ordering::Acquire);
+        let template = match work.template.read().unwrap().clone() {
+            Some(template) if index < num_threads => template,
+            _ => {
+                thread::sleep(IDLE_INTERVAL);
+                continue;
+            }
+        };
+        let (first, end) = nonce_range(index, num_threads);
+        let mut header = template.header.clone();
+        'search: loop {
+            for nonce in first..end {
+                if work.generation.load(Ordering::Acquire) != generation {
+                    break 'search;
+                }
+                let lambda = work.lambda.load(Ordering::Relaxed);
+                if lambda != 0 {
+                    thread::sleep(time::Duration::from_micros(lambda));
+                }
+                header.nonce = nonce as u32;
+                hash_count.fetch_add(1, Ordering::Relaxed);
+                if header.hash() <= header.difficulty {
+                    let block = Block { header, content: template.content.clone() };
+                    solution_chan.send(block).unwrap();
+                    // wait for the miner to replace the template:
+                    while work.generation.load(Ordering::Acquire) == generation {
+                        thread::sleep(IDLE_INTERVAL);
+                    }
+                    break 'search;
+                }
+            }
+            // the range is exhausted, so take the current time to get fresh hashes, never getting
+            // ahead of the clock:
+            header.timestamp = clock.now_ms();
+        }
+    }
 }
 
 impl Context {
     pub fn start(mut self) {
+        self.spawn_hashing_threads();
+        info!("Miner initialized into paused mode with {} hashing threads", self.num_threads);
         thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
-        info!("Miner initialized into paused mode");
+    }
+
+    /// Spawn hashing threads until there are `num_threads`. Threads are never stopped, only
+    /// left idle when there are more than `num_threads`.
+    fn spawn_hashing_threads(&mut self) {
+        let mut hash_counts = self.work.hash_counts.write().unwrap();
+        for i in hash_counts.len()..self.num_threads {
+            let hash_count = Arc::new(AtomicU64::new(0));
+            hash_counts.push(Arc::clone(&hash_count));
+            self.last_hash_counts.push(0);
+            let work = Arc::clone(&self.work);
+            let solution_chan = self.solution_sender.clone();
+            let clock = Arc::clone(&self.clock);
+            thread::Builder::new()
+                .name(format!("miner-{}", i))
+                .spawn(move || {
+                    hashing_loop(&work, i, &hash_count, &solution_chan, clock.as_ref());
+                })
+                .unwrap();
+        }
+        self.work.active_threads.store(self.num_threads, Ordering::Release);
+    }
+
+    /// Hand a new template (or none, to idle) to the hashing threads
+    fn publish(&mut self, template: Option<Arc<BlockTemplate>>) {
+        self.template = template.clone();
+        *self.work.template.write().unwrap() = template;
+        self.work.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn hash_counts(&self) -> Vec<u64> {
+        self.work.hash_counts.read().unwrap().iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// The hashes per second of each active hashing thread since the last report
+    fn hash_rates(&self) -> Vec<f64> {
+        let seconds = self.last_report.elapsed().as_secs_f64();
+        self.hash_counts().iter().zip(&self.last_hash_counts)
+            .take(self.num_threads)
+            .map(|(count, last)| (count - last) as f64 / seconds)
+            .collect()
+    }
+
+    /// Log the hash rates, and start measuring them anew
+    fn report_hash_rates(&mut self) {
+        info!("Hashes per second of each thread: {:?}", self.hash_rates());
+        self.last_hash_counts = self.hash_counts();
+        self.last_report = Instant::now();
+    }
+
+    fn seconds_since(&self, ms: u128) -> f64 {
+        self.clock.now_ms().saturating_sub(ms) as f64 / 1000.0
+    }
+
+    fn status(&self) -> MinerStatus {
+        let (state, lambda, rate) = match self.operating_state {
+            OperatingState::Run(lambda) => ("running", Some(lambda), None),
+            OperatingState::Simulate(rate) => ("simulating", None, Some(rate)),
+            _ => ("paused", None, None),
+        };
+        let stale_blocks = {
+            let blockchain = self.blockchain.lock().unwrap();
+            self.mined_blocks.iter().filter(|hash| !blockchain.is_in_longest_chain(hash)).count()
+        };
+        MinerStatus {
+            state: state.to_string(),
+            lambda,
+            rate,
+            threads: self.num_threads,
+            payout_address: self.payout_address,
+            hash_rates: self.hash_rates(),
+            blocks_mined: self.total_blocks_mined,
+            stale_blocks,
+            stale_work_ms: self.stale_work_ms,
+            uptime_seconds: self.start_ms.map_or(0.0, |start_ms| self.seconds_since(start_ms)),
+        }
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
         match signal {
+            ControlSignal::Pause => {
+                if let OperatingState::Run(_) | OperatingState::Simulate(_) = self.operating_state {
+                    info!("Miner paused");
+                    self.paused_state = Some(self.operating_state);
+                    self.operating_state = OperatingState::Paused;
+                    self.publish(None);
+                }
+            }
+            ControlSignal::Resume => {
+                if let Some(state) = self.paused_state.take() {
+                    info!("Miner resumed");
+                    self.operating_state = state;
+                    self.next_block_at_ms = None;
+                }
+            }
+            ControlSignal::Config { payout_address, threads } => {
+                if let Some(address) = payout_address {
+                    info!("Miner paying blocks to {}", address);
+                    self.payout_address = address;
+                }
+                if let Some(threads) = threads {
+                    info!("Miner searching nonces on {} threads", threads);
+                    self.num_threads = threads;
+                    self.spawn_hashing_threads();
+                }
+                // assemble and hand out a new template, paying the new address or split differently:
+                self.template = None;
+            }
+            ControlSignal::Status(reply_chan) => {
+                let status = self.status();
+                let _ = reply_chan.send(status);
+            }
             ControlSignal::Exit => {
                 info!("Miner shutting down");
                 self.operating_state = OperatingState::ShutDown;
+                self.publish(None);
 
                 // print mining stats if the miner started:
-                if let Some(start_time) = self.start_time {
-                    let seconds_spent = SystemTime::now().duration_since(start_time).unwrap().as_secs_f64();
+                if let Some(start_ms) = self.start_ms {
+                    let seconds_spent = self.seconds_since(start_ms);
                     let mining_rate = (self.total_blocks_mined as f64) / seconds_spent;
                     info!("Mined {} blocks in {} seconds, rate is {} blocks/second",
                         self.total_blocks_mined, seconds_spent, mining_rate);
+                    self.report_hash_rates();
+                    info!("Spent {} ms on stale work over {} templates",
+                        self.stale_work_ms, self.stale_templates);
                     let blockchain = self.blockchain.lock().unwrap();
                     info!("Blockchain has {} blocks in total", blockchain.block_count());
                     let longest_chain = blockchain.all_blocks_in_longest_chain();
@@ -114,18 +426,96 @@ impl Context {
                     info!("Delays in ms for each block (raw data): {:?}", blockchain.block_delays_ms());
                 }
             }
+            ControlSignal::Simulate(rate) => {
+                info!("Miner simulating block discovery at {} blocks/second", rate);
+                self.operating_state = OperatingState::Simulate(rate);
+                self.paused_state = None;
+                self.publish(None);
+                self.next_block_at_ms = None;
+
+                if self.start_ms.is_none() {
+                    self.start_ms = Some(self.clock.now_ms());
+                    self.last_report = Instant::now();
+                }
+            }
             ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
                 self.operating_state = OperatingState::Run(i);
+                self.paused_state = None;
+                // hand the template to the hashing threads, even if still fresh:
+                self.template = None;
 
                 // set the miner start time:
-                if self.start_time == None {
-                    self.start_time = Some(SystemTime::now());
+                if self.start_ms.is_none() {
+                    self.start_ms = Some(self.clock.now_ms());
+                    self.last_report = Instant::now();
                 }
             }
         }
     }
 
+    /// Every so often, briefly take the locks to assemble a new template if the current one is
+    /// stale. Returns the new template, if any.
+    fn refresh_template(&mut self) -> Option<Arc<BlockTemplate>> {
+        if self.template.is_some() && self.last_template_check.elapsed() < TEMPLATE_CHECK_INTERVAL {
+            return None;
+        }
+        self.last_template_check = Instant::now();
+        let blockchain = self.blockchain.lock().unwrap();
+        let mempool = self.mempool.lock().unwrap();
+        if self.template.as_ref().is_none_or(|template| template.is_stale(&blockchain, &mempool)) {
+            let payouts = [(self.payout_address, 1)];
+            Some(Arc::new(BlockTemplate::assemble(&blockchain, &mempool, &payouts, self.clock.now_ms())))
+        } else {
+            None
+        }
+    }
+
+    /// Insert a block we found into the blockchain and announce it, unless another block was
+    /// found on the same parent in the meantime (e.g. by another hashing thread)
+    fn insert_mined(&mut self, block: Block) {
+        self.template = None;
+        let mut blockchain = self.blockchain.lock().unwrap();
+        if block.header.parent != blockchain.tip() {
+            return;
+        }
+        let mut mempool = self.mempool.lock().unwrap();
+        let old_tip = blockchain.tip();
+        blockchain.insert(&block);
+        mempool.update_tip(&old_tip, &blockchain);
+        self.total_blocks_mined += 1;
+        self.mined_blocks.push(block.hash());
+        self.server.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+        blockchain.hash_to_origin.insert(block.hash(), BlockOrigin::Mined);
+    }
+
+    /// Abandon the template at once if it is not on the tip. A notification only tells when the tip
+    /// first changed, as later changes are not sent until it is received, so the tip is read from
+    /// the blockchain.
+    fn handle_tip_change(&mut self, change: TipChange) {
+        let tip = self.blockchain.lock().unwrap().tip();
+        if let Some(template) = self.template.as_ref().filter(|template| template.header.parent != tip) {
+            // the template went stale when the tip first changed, unless it was assembled later:
+            let stale_since_ms = change.time.max(template.header.timestamp);
+            self.stale_work_ms += self.clock.now_ms().saturating_sub(stale_since_ms);
+            self.stale_templates += 1;
+            self.template = None;
+        }
+    }
+
+    /// Wait up to `timeout` for a block from the hashing threads, returning early without one
+    /// if the tip changes
+    fn wait_for_solution(&mut self, timeout: time::Duration) -> Option<Block> {
+        select! {
+            recv(self.solution_chan) -> block => Some(block.expect("Miner solution channel detached")),
+            recv(self.tip_chan) -> change => {
+                self.handle_tip_change(change.expect("Blockchain tip channel detached"));
+                None
+            }
+            default(timeout) => None,
+        }
+    }
+
     fn miner_loop(&mut self) {
         // main mining loop
         loop {
@@ -151,51 +541,91 @@ impl Context {
                 return;
             }
 
-
             if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
-                }
-
-                let mut blockchain = self.blockchain.lock().unwrap();
-                let mut mempool = self.mempool.lock().unwrap();
-
-                let parent = blockchain.tip();
-                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                let difficulty = blockchain.get_block(&parent).header.difficulty;
-                let mut transactions: Vec<Transaction> = vec![Default::default()];
-                let merkle_root = MerkleTree::new(&transactions).root();
-                let nonce = rand::random();
-        
-                let header = Header {
-                    parent,
-                    nonce,
-                    difficulty,
-                    timestamp,
-                    merkle_root, 
-                };
-                if transactions.len() < 20 {
-                    while transactions.len() < 20{
-                        let trans = mempool.pop();
-                        if !trans.is_none(){
-                            transactions.push(trans.unwrap().clone());
-                        }
-                        else{
-                            break;
-                        }
-                    }
-                } 
-                let content = Content { transactions };
-                let block = Block { header, content };
+                self.work.lambda.store(i, Ordering::Relaxed);
+                if let Some(template) = self.refresh_template() {
+                    self.publish(Some(template));
+                }
+
+                // wait for the hashing threads until the next template check:
+                if let Some(block) = self.wait_for_solution(TEMPLATE_CHECK_INTERVAL) {
+                    self.insert_mined(block);
+                }
+
+                if self.last_report.elapsed() >= HASH_RATE_REPORT_INTERVAL {
+                    self.report_hash_rates();
+                }
+            }
 
-                if block.hash() <= difficulty {
-                    blockchain.insert(&block);
-                    self.total_blocks_mined += 1;
-                    self.server.broadcast(Message::NewBlockHashes(vec![block.hash()]));
-                    blockchain.hash_to_origin.insert(block.hash(), BlockOrigin::Mined);
+            if let OperatingState::Simulate(rate) = self.operating_state {
+                // the hashing threads stay idle, so keep the template to ourselves:
+                if let Some(template) = self.refresh_template() {
+                    self.template = Some(template);
+                }
+                // block discovery is a Poisson process, so the time to the next block is exponential:
+                let rng = &mut self.rng;
+                let now_ms = self.clock.now_ms();
+                let next_block_at_ms = *self.next_block_at_ms.get_or_insert_with(|| {
+                    let seconds: f64 = rng.sample(Exp::new(rate));
+                    now_ms + (seconds * 1000.0) as u128
+                });
+                if now_ms >= next_block_at_ms {
+                    let block = self.template.as_ref().unwrap().block(self.rng.gen());
+                    self.next_block_at_ms = None;
+                    self.insert_mined(block);
+                } else {
+                    // the clock may not be the wall clock, so check it again at least every template check:
+                    let remaining = time::Duration::from_millis((next_block_at_ms - now_ms).min(u64::MAX as u128) as u64);
+                    self.wait_for_solution(TEMPLATE_CHECK_INTERVAL.min(remaining));
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::clock::SystemClock;
+    use crate::network::server;
+    use rand::SeedableRng;
+
+    #[test]
+    fn nonce_ranges_cover_the_nonce_space() {
+        for num_threads in &[1, 2, 3, 7, 64] {
+            let ranges: Vec<(u64, u64)> = (0..*num_threads).map(|i| nonce_range(i, *num_threads)).collect();
+            // the ranges are contiguous and disjoint, from the first nonce to the last:
+            assert_eq!(ranges[0].0, 0);
+            assert_eq!(ranges.last().unwrap().1, NONCE_SPACE);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].1, pair[1].0);
+            }
+            // and of even sizes, but for the remainder left to the last:
+            let size = NONCE_SPACE / *num_threads as u64;
+            assert!(ranges[..ranges.len() - 1].iter().all(|(first, end)| end - first == size));
+            assert!(ranges.last().map(|(first, end)| end - first).unwrap() < 2 * size);
+        }
+    }
+
+    #[test]
+    fn two_tip_changes_in_a_row() {
+        let (msg_sender, _msg_receiver) = unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_sender).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let (mut ctx, _handle) = new(&server, &blockchain, &mempool, 1, H160::default(), StdRng::seed_from_u64(0), &clock);
+
+        // the template moves to the first new tip before its notification is received:
+        let first = generate_random_block(&blockchain.lock().unwrap().tip());
+        blockchain.lock().unwrap().insert(&first);
+        ctx.template = ctx.refresh_template();
+        assert_eq!(ctx.template.as_ref().unwrap().header.parent, first.hash());
+        // and the second new tip is not notified, as the first notification is still pending:
+        blockchain.lock().unwrap().insert(&generate_random_block(&first.hash()));
+        assert!(ctx.wait_for_solution(time::Duration::from_millis(100)).is_none());
+        assert!(ctx.template.is_none());
+        assert_eq!(ctx.stale_templates, 1);
+    }
+}
@@ -6,12 +6,11 @@ use log::{debug, warn};
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 use crate::blockchain::Blockchain;
-use crate::mempool::Mempool;
+use crate::mempool::{Mempool, RejectReason};
 use crate::crypto::hash::{Hashable, H256};
 use crate::blockchain::BlockOrigin;
 use crate::transaction::SignedTransaction;
-use crate::transaction::State;
-use crate::address::H160;
+use crate::verifier::Handle as VerifierHandle;
 
 use std::thread;
 
@@ -21,7 +20,8 @@ pub struct Context {
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<Mempool>>
+    mempool: Arc<Mutex<Mempool>>,
+    verifier: VerifierHandle,
 }
 
 pub fn new(
@@ -30,6 +30,7 @@ pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    verifier: &VerifierHandle,
 ) -> Context {
     Context {
         msg_chan: msg_src,
@@ -37,6 +38,7 @@ pub fn new(
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        verifier: verifier.clone(),
     }
 }
 
@@ -89,13 +91,16 @@ impl Context {
                 Message::Blocks(blocks) => {
                     debug!("Blocks: {:?}", blocks);
                     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                    // verify all signatures before taking the lock:
+                    let signers = self.verifier.verify_blocks(&blocks);
                     let mut blockchain = self.blockchain.lock().unwrap();
+                    let old_tip = blockchain.tip();
                     let mut relay_hashes = Vec::new();
                     let mut missing_hashes = Vec::new();
-                    for block in blocks {
+                    for (block, signers) in blocks.into_iter().zip(signers) {
                         // For experiment: record the block delay; don't count redundant or self-mined blocks:
                         blockchain.hash_to_origin.entry(block.hash())
-                            .or_insert(BlockOrigin::Received{ delay_ms: now - block.header.timestamp });
+                            .or_insert(BlockOrigin::Received{ delay_ms: now.saturating_sub(block.header.timestamp) });
                         // Regular processing:
                         if blockchain.contains_block(&block.hash()) {
                             continue;
@@ -104,17 +109,27 @@ impl Context {
                             warn!("PoW check failed");
                             continue;
                         }
+                        if !Blockchain::merkle_check(&block) {
+                            warn!("Merkle root check failed");
+                            continue;
+                        }
                         if !blockchain.parent_check(&block) {
-                            blockchain.add_to_orphan_buffer(&block);
+                            blockchain.add_to_orphan_buffer(&block, signers);
                             missing_hashes.push(block.header.parent);
                             continue;
                         }
+                        if !blockchain.spending_check_block(&block, &signers) {
+                            warn!("Transaction check failed");
+                            continue;
+                        }
                         let tx_vec = block.content.transactions.clone();
-                        //DO TRANSACTION CHECKS
                         peer.write(Message::Transactions(tx_vec));
 
                         blockchain.insert_recursively(&block, &mut relay_hashes);
                     }
+                    if blockchain.tip() != old_tip {
+                        self.mempool.lock().unwrap().update_tip(&old_tip, &blockchain);
+                    }
                     if !missing_hashes.is_empty() {
                         peer.write(Message::GetBlocks(missing_hashes));
                     }
@@ -147,8 +162,10 @@ impl Context {
                     let mempool = self.mempool.lock().unwrap();
                     let mut tx_list: Vec<SignedTransaction> = vec![];
                     for tx_hash in transaction_hashes {
-                        let this_tx = mempool.get_transaction(&tx_hash).unwrap();
-                        tx_list.push(this_tx.clone());
+                        // the transaction may have been mined or evicted since it was announced
+                        if let Some(this_tx) = mempool.get_transaction(&tx_hash) {
+                            tx_list.push(this_tx.clone());
+                        }
                     }
                     if !tx_list.is_empty() {
                         peer.write(Message::Transactions(tx_list));
@@ -156,44 +173,19 @@ impl Context {
                 }
                 Message::Transactions(transactions) => {
                     debug!("Transactions: {:?}", transactions);
-                    let mut blockchain = self.blockchain.lock().unwrap();
+                    // verify all signatures before taking the locks:
+                    let signers = self.verifier.verify(&transactions);
+                    let blockchain = self.blockchain.lock().unwrap();
                     let mut mempool = self.mempool.lock().unwrap();
-                    let tip = blockchain.tip();
                     let mut valid_tx: Vec<H256> = Vec::new();
-                    for tx in transactions {
-                        if !tx.verify_signature(){
-                            continue;
-                        }
-                        let mut valid = true;
-                        //signature check
-                        let publickey = tx.pub_key.clone();
-                        let transaction_raw = tx.raw.clone();
-                        let transaction_raw_input = transaction_raw.TransactionInput;
-                        let curr_state = blockchain.get_state(&tip.clone());
-                        for each_input in transaction_raw_input{
-                            let each_output = curr_state.get(&each_input);
-                            //check if input values actually exist
-                            if each_output.is_none(){
-                                valid = false;
-                                break;
-                            }
-                            let address = each_output.unwrap().recipient;
-                            let pka = H160::from_pubkey(&publickey);
-                            if address != pka{
-                                valid = false;
-                                break;
-                            }
-                            //check that values of inputs are not less than outputs
-                            let input_value = each_input.prev_tx;
-                            let output_value = each_output.unwrap().value;
-                            if input_value < output_value{
-                                valid = false;
-                                break;
-                            }
-                        }
-                        if valid {
-                            valid_tx.push(tx.hash());
-                            mempool.insert(tx);
+                    for (tx, signers) in transactions.into_iter().zip(signers) {
+                        let result = match signers {
+                            Some(signers) => mempool.accept_verified(tx, &signers, &blockchain),
+                            None => Err(RejectReason::Invalid),
+                        };
+                        match result {
+                            Ok(hash) => valid_tx.push(hash),
+                            Err(reason) => debug!("Rejected transaction: {}", reason),
                         }
                     }
                     /* if !missing_hashes.is_empty() {
@@ -0,0 +1,381 @@
+use serde::{Serialize, Deserialize};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use crate::address::H160;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::mempool::Mempool;
+use crate::network::message::Message;
+use crate::network::server::Handle as ServerHandle;
+use crate::transaction::{Script, SignedTransaction, Transaction, TransactionInput, TransactionOutput};
+use crate::transaction_builder::TransactionBuilder;
+use crate::policy::DEFAULT_DUST_THRESHOLD;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The fee of the funding, commitment and refund transactions, paid by the payer
+const CHANNEL_FEE: u64 = 1_000;
+/// How many blocks before its timeout the payee stops accepting commitments on a channel, so
+/// that it can still close the channel before the payer may take the funds back
+const CLOSE_SAFETY_MARGIN: u64 = 2;
+
+/// Which end of a unidirectional payment channel this node is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Payer,
+    Payee,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    /// The funding transaction has been broadcast, and commitments can be exchanged
+    Open,
+    /// The payee has broadcast the latest commitment, or (seen by the payer) the funding output
+    /// has been spent on the longest chain
+    Closed,
+    /// The payer has taken the funds back after the timeout
+    Refunded,
+}
+
+/// A unidirectional payment channel. The payer locks `capacity` in a 2-of-2 funding output,
+/// then pays the payee off-chain by signing commitment transactions that spend the funding
+/// output to both parties. The payee closes the channel by co-signing and broadcasting the latest
+/// commitment before `timeout`, after which the payer can take all the funds back alone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Channel {
+    pub role: Role,
+    /// The funding output, always at index 0 of the funding transaction
+    pub funding: TransactionInput,
+    pub capacity: u64,
+    pub payer: H160,
+    pub payee: H160,
+    pub timeout: u64,
+    /// The total value paid to the payee by the latest commitment
+    pub paid: u64,
+    /// The latest commitment, signed by the payer
+    pub latest: Option<SignedTransaction>,
+    pub state: ChannelState,
+    /// Whether the funding output has been seen on the longest chain, after which its absence
+    /// means that it was spent
+    pub confirmed: bool,
+}
+
+/// Tracks the payment channels of the key controlled by this node
+pub struct ChannelManager {
+    key: Ed25519KeyPair,
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    /// Channels by the hash of their funding transaction
+    channels: HashMap<H256, Channel>,
+}
+
+impl ChannelManager {
+    pub fn new(
+        server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        key: Ed25519KeyPair,
+    ) -> Self {
+        ChannelManager {
+            key,
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            channels: HashMap::new(),
+        }
+    }
+
+    fn address(&self) -> H160 {
+        H160::from_pubkey(self.key.public_key().as_ref())
+    }
+
+    pub fn channels(&self) -> &HashMap<H256, Channel> {
+        &self.channels
+    }
+
+    /// Check a transaction against the tip, add it to the mempool and send it to all peers
+    fn broadcast(&self, tx: SignedTransaction) -> Result<H256, String> {
+        let blockchain = self.blockchain.lock().unwrap();
+        let hash = self.mempool.lock().unwrap().accept(tx.clone(), &blockchain)
+            .map_err(|reason| reason.to_string())?;
+        self.server.broadcast(Message::Transactions(vec![tx]));
+        Ok(hash)
+    }
+
+    /// Bring the outgoing channels up to date with the longest chain: once confirmed, a funding
+    /// output that leaves the state was spent by the payee closing the channel
+    pub fn update(&mut self) {
+        let blockchain = self.blockchain.lock().unwrap();
+        let state = blockchain.get_state(&blockchain.tip());
+        for channel in self.channels.values_mut() {
+            if channel.role != Role::Payer || channel.state != ChannelState::Open {
+                continue;
+            }
+            if state.contains_key(&channel.funding) {
+                channel.confirmed = true;
+            } else if channel.confirmed {
+                channel.state = ChannelState::Closed;
+            }
+        }
+    }
+
+    /// Open a channel to `payee` by locking `capacity` in a funding output that times out after
+    /// `timeout` blocks. Returns the channel id.
+    pub fn open(&mut self, payee: H160, capacity: u64, timeout: u64) -> Result<H256, String> {
+        let me = self.address();
+        let (tx, timeout) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            let timeout = blockchain.get_height(&tip) + timeout;
+            let tx = TransactionBuilder::new(&self.key)
+                .output(TransactionOutput {
+                    recipient: me,
+                    value: capacity,
+                    script: Script::TwoOfTwo { cosigner: payee, timeout },
+                })
+                .fee(CHANNEL_FEE)
+                .build(blockchain.get_state(&tip))?;
+            (tx, timeout)
+        };
+        let id = tx.raw.hash();
+        self.broadcast(tx)?;
+        self.channels.insert(id, Channel {
+            role: Role::Payer,
+            funding: TransactionInput { txid: 0, prev_tx: id },
+            capacity,
+            payer: me,
+            payee,
+            timeout,
+            paid: 0,
+            latest: None,
+            state: ChannelState::Open,
+            confirmed: false,
+        });
+        Ok(id)
+    }
+
+    /// Pay `amount` more to the payee. Returns the new commitment, to be sent to the payee off-chain.
+    pub fn pay(&mut self, id: &H256, amount: u64) -> Result<SignedTransaction, String> {
+        self.update();
+        let channel = self.channels.get(id).ok_or("unknown channel")?;
+        if channel.role != Role::Payer || channel.state != ChannelState::Open {
+            return Err("not an open outgoing channel".to_string());
+        }
+        let paid = channel.paid.checked_add(amount).ok_or("insufficient channel capacity")?;
+        let remainder = channel.capacity.checked_sub(paid)
+            .and_then(|remainder| remainder.checked_sub(CHANNEL_FEE))
+            .ok_or("insufficient channel capacity")?;
+        let mut outputs = vec![TransactionOutput { recipient: channel.payee, value: paid, script: Script::PayToAddress }];
+        // leave a dust remainder as extra fee:
+        if remainder >= DEFAULT_DUST_THRESHOLD {
+            outputs.push(TransactionOutput { recipient: channel.payer, value: remainder, script: Script::PayToAddress });
+        }
+        let raw = Transaction { TransactionInput: vec![channel.funding], TransactionOutput: outputs };
+        let commitment = SignedTransaction::from_raw(raw, &self.key);
+        let channel = self.channels.get_mut(id).unwrap();
+        channel.paid = paid;
+        channel.latest = Some(commitment.clone());
+        Ok(commitment)
+    }
+
+    /// Accept a commitment from the payer of an incoming channel, registering the channel from
+    /// its funding output on first use. Returns the total value paid so far.
+    pub fn receive(&mut self, commitment: SignedTransaction) -> Result<u64, String> {
+        let me = self.address();
+        let funding = match commitment.raw.TransactionInput[..] {
+            [funding] => funding,
+            _ => return Err("a commitment spends exactly the funding output".to_string()),
+        };
+        let height = {
+            let blockchain = self.blockchain.lock().unwrap();
+            blockchain.get_height(&blockchain.tip())
+        };
+        if !self.channels.contains_key(&funding.prev_tx) {
+            let blockchain = self.blockchain.lock().unwrap();
+            let output = blockchain.get_state(&blockchain.tip()).get(&funding).cloned()
+                .ok_or("funding output not confirmed")?;
+            let timeout = match output.script {
+                Script::TwoOfTwo { cosigner, timeout } if cosigner == me => timeout,
+                _ => return Err("not a funding output of a channel to this node".to_string()),
+            };
+            self.channels.insert(funding.prev_tx, Channel {
+                role: Role::Payee,
+                funding,
+                capacity: output.value,
+                payer: output.recipient,
+                payee: me,
+                timeout,
+                paid: 0,
+                latest: None,
+                state: ChannelState::Open,
+                confirmed: true,
+            });
+        }
+        let channel = self.channels.get_mut(&funding.prev_tx).unwrap();
+        if channel.role != Role::Payee || channel.state != ChannelState::Open {
+            return Err("not an open incoming channel".to_string());
+        }
+        if height.saturating_add(CLOSE_SAFETY_MARGIN) >= channel.timeout {
+            return Err("too close to the channel timeout to close it safely".to_string());
+        }
+        if !commitment.verify_signature() || commitment.signer() != channel.payer {
+            return Err("commitment not signed by the payer".to_string());
+        }
+        let outputs = &commitment.raw.TransactionOutput;
+        let total = outputs.iter().try_fold(0u64, |sum, output| sum.checked_add(output.value));
+        if !matches!(total, Some(total) if total <= channel.capacity) {
+            return Err("commitment spends more than the channel capacity".to_string());
+        }
+        // bounded by the total, so it cannot overflow:
+        let paid = outputs.iter()
+            .filter(|output| output.recipient == me && output.script == Script::PayToAddress)
+            .map(|output| output.value)
+            .sum();
+        if paid <= channel.paid {
+            return Err(format!("commitment pays {}, not more than the previous {}", paid, channel.paid));
+        }
+        channel.paid = paid;
+        channel.latest = Some(commitment);
+        Ok(paid)
+    }
+
+    /// Close an incoming channel by co-signing and broadcasting the latest commitment
+    pub fn close(&mut self, id: &H256) -> Result<H256, String> {
+        let channel = self.channels.get(id).ok_or("unknown channel")?;
+        if channel.role != Role::Payee || channel.state != ChannelState::Open {
+            return Err("not an open incoming channel".to_string());
+        }
+        let mut commitment = channel.latest.clone().ok_or("no commitment received")?;
+        commitment.cosign(&self.key);
+        let hash = self.broadcast(commitment)?;
+        self.channels.get_mut(id).unwrap().state = ChannelState::Closed;
+        Ok(hash)
+    }
+
+    /// Take back the funds of an outgoing channel the payee did not close before its timeout
+    pub fn refund(&mut self, id: &H256) -> Result<H256, String> {
+        self.update();
+        let channel = self.channels.get(id).ok_or("unknown channel")?;
+        if channel.role != Role::Payer || channel.state != ChannelState::Open {
+            return Err("not an open outgoing channel".to_string());
+        }
+        let value = channel.capacity.checked_sub(CHANNEL_FEE).ok_or("channel capacity below the fee")?;
+        let raw = Transaction {
+            TransactionInput: vec![channel.funding],
+            TransactionOutput: vec![TransactionOutput {
+                recipient: channel.payer,
+                value,
+                script: Script::PayToAddress,
+            }],
+        };
+        let hash = self.broadcast(SignedTransaction::from_raw(raw, &self.key))?;
+        self.channels.get_mut(id).unwrap().state = ChannelState::Refunded;
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::network::server;
+    use crossbeam::channel::unbounded;
+
+    /// A payer and a payee on the same chain, funded by the initial coin offering
+    fn managers() -> (server::Context, ChannelManager, ChannelManager) {
+        let (msg_sender, _msg_receiver) = unbounded();
+        let (server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_sender).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let key = |seed| Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+        let payer = ChannelManager::new(&server, &blockchain, &mempool, key(100));
+        let payee = ChannelManager::new(&server, &blockchain, &mempool, key(200));
+        (server_ctx, payer, payee)
+    }
+
+    /// Confirm the mempool in a block, then add `empty` blocks
+    fn mine(manager: &ChannelManager, empty: usize) {
+        let mut blockchain = manager.blockchain.lock().unwrap();
+        let mut mempool = manager.mempool.lock().unwrap();
+        let mut block = generate_random_block(&blockchain.tip());
+        while let Some(tx) = mempool.pop() {
+            block.content.transactions.push(tx);
+        }
+        blockchain.insert(&block);
+        for _ in 0..empty {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+    }
+
+    #[test]
+    fn pay_and_receive() {
+        let (_server_ctx, mut payer, mut payee) = managers();
+        let id = payer.open(payee.address(), 10_000, 10).unwrap();
+        let first = payer.pay(&id, 3_000).unwrap();
+        // the funding must confirm before the payee trusts it:
+        assert!(payee.receive(first.clone()).is_err());
+        mine(&payer, 0);
+        assert_eq!(payee.receive(first.clone()), Ok(3_000));
+        assert_eq!(payee.receive(first), Err("commitment pays 3000, not more than the previous 3000".to_string()));
+
+        let second = payer.pay(&id, 2_000).unwrap();
+        assert_eq!(payee.receive(second), Ok(5_000));
+        assert_eq!(payer.pay(&id, 5_001).err(), Some("insufficient channel capacity".to_string()));
+        assert_eq!(payer.pay(&id, u64::MAX).err(), Some("insufficient channel capacity".to_string()));
+
+        // a commitment whose outputs overflow is refused:
+        let mut overflowing = payer.channels()[&id].latest.clone().unwrap();
+        overflowing.raw.TransactionOutput[0].value = u64::MAX;
+        overflowing.raw.TransactionOutput[1].value = u64::MAX;
+        let overflowing = SignedTransaction::from_raw(overflowing.raw, &payer.key);
+        assert_eq!(payee.receive(overflowing), Err("commitment spends more than the channel capacity".to_string()));
+    }
+
+    #[test]
+    fn close_or_refund() {
+        let (_server_ctx, mut payer, mut payee) = managers();
+        let closed = payer.open(payee.address(), 10_000, 5).unwrap();
+        mine(&payer, 0);
+        let refunded = payer.open(payee.address(), 10_000, 5).unwrap();
+        mine(&payer, 0);
+        payee.receive(payer.pay(&closed, 3_000).unwrap()).unwrap();
+        payee.receive(payer.pay(&refunded, 3_000).unwrap()).unwrap();
+
+        // before the timeout, the payer cannot take the funds back alone:
+        assert!(payer.refund(&refunded).is_err());
+        payee.close(&closed).unwrap();
+        assert_eq!(payee.channels()[&closed].state, ChannelState::Closed);
+        mine(&payee, 2);
+        let blockchain = payee.blockchain.lock().unwrap();
+        let commitment = payee.channels()[&closed].latest.as_ref().unwrap().raw.hash();
+        let payee_output = TransactionInput { txid: 0, prev_tx: commitment };
+        assert_eq!(blockchain.get_state(&blockchain.tip())[&payee_output].value, 3_000);
+        drop(blockchain);
+
+        // the payer sees the closed channel spent, while the other one can be refunded after its
+        // timeout:
+        assert!(payer.refund(&closed).is_err());
+        assert_eq!(payer.channels()[&closed].state, ChannelState::Closed);
+        assert_eq!(payer.pay(&closed, 1_000).err(), Some("not an open outgoing channel".to_string()));
+        payer.refund(&refunded).unwrap();
+        assert_eq!(payer.channels()[&refunded].state, ChannelState::Refunded);
+        mine(&payer, 0);
+        assert!(payee.close(&refunded).is_err());
+    }
+
+    #[test]
+    fn receive_near_timeout() {
+        let (_server_ctx, mut payer, mut payee) = managers();
+        let id = payer.open(payee.address(), 10_000, 10).unwrap();
+        mine(&payer, 0);
+        payee.receive(payer.pay(&id, 1_000).unwrap()).unwrap();
+        mine(&payer, 5);
+        assert_eq!(payee.receive(payer.pay(&id, 1_000).unwrap()), Ok(2_000));
+        // past the safety margin, the payee could not be sure to close the channel in time:
+        mine(&payer, 0);
+        let late = payer.pay(&id, 1_000).unwrap();
+        assert_eq!(payee.receive(late), Err("too close to the channel timeout to close it safely".to_string()));
+        assert_eq!(payee.channels()[&id].paid, 2_000);
+    }
+}
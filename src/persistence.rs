@@ -0,0 +1,115 @@
+use crate::blockchain::Blockchain;
+use crate::fee_estimator::FeeEstimator;
+use crate::mempool::Mempool;
+
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// How often the node state is saved
+const SAVE_INTERVAL_SECONDS: u64 = 60;
+/// How often the persistence thread checks for a termination signal
+const SIGNAL_CHECK_MILLISECONDS: u64 = 100;
+const MEMPOOL_FILE: &str = "mempool.dat";
+const FEE_ESTIMATES_FILE: &str = "fee_estimates.dat";
+
+/// Saves the parts of the node state that should survive a restart (e.g. pending transactions)
+/// to files in a data directory, periodically and on shutdown (through the API, SIGINT or SIGTERM)
+#[derive(Clone)]
+pub struct Persistence {
+    data_dir: PathBuf,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+}
+
+/// Write `data` to `path` through a temporary file, so that a crash never leaves a partial file
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(tmp_path, path)
+}
+
+/// Set by the handler of SIGINT and SIGTERM, which may only do async-signal-safe work
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_termination_signal(_signal: libc::c_int) {
+    TERMINATING.store(true, Ordering::SeqCst);
+}
+
+impl Persistence {
+    pub fn new(
+        data_dir: &Path,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        Ok(Persistence {
+            data_dir: data_dir.to_path_buf(),
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+        })
+    }
+
+    /// Load the state saved by a previous run, if any, checking it against the current tip
+    pub fn load(&self) {
+        let path = self.data_dir.join(FEE_ESTIMATES_FILE);
+        if path.exists() {
+            match FeeEstimator::load(&path) {
+                Ok(fee_estimator) => self.mempool.lock().unwrap().set_fee_estimator(fee_estimator),
+                Err(e) => warn!("Error loading {}: {}", path.display(), e),
+            }
+        }
+        let path = self.data_dir.join(MEMPOOL_FILE);
+        if path.exists() {
+            let blockchain = self.blockchain.lock().unwrap();
+            match self.mempool.lock().unwrap().load(&path, &blockchain) {
+                Ok(count) => info!("Reloaded {} mempool transactions from {}", count, path.display()),
+                Err(e) => warn!("Error loading {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let mempool = self.mempool.lock().unwrap();
+        let path = self.data_dir.join(MEMPOOL_FILE);
+        if let Err(e) = mempool.dump(&path) {
+            warn!("Error saving {}: {}", path.display(), e);
+        }
+        let path = self.data_dir.join(FEE_ESTIMATES_FILE);
+        if let Err(e) = mempool.fee_estimator().dump(&path) {
+            warn!("Error saving {}: {}", path.display(), e);
+        }
+    }
+
+    /// Save the state periodically, and on SIGINT or SIGTERM before exiting
+    pub fn start(self) {
+        let handler = on_termination_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGTERM, handler);
+        }
+        thread::Builder::new()
+            .name("persistence".to_string())
+            .spawn(move || {
+                let mut last_save = time::Instant::now();
+                loop {
+                    thread::sleep(time::Duration::from_millis(SIGNAL_CHECK_MILLISECONDS));
+                    if TERMINATING.load(Ordering::SeqCst) {
+                        self.save();
+                        info!("Shutting down");
+                        std::process::exit(0);
+                    }
+                    if last_save.elapsed() >= time::Duration::from_secs(SAVE_INTERVAL_SECONDS) {
+                        self.save();
+                        last_save = time::Instant::now();
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
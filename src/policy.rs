@@ -0,0 +1,204 @@
+use crate::transaction::{Script, SignedTransaction};
+
+pub const DEFAULT_DUST_THRESHOLD: u64 = 100;
+pub const DEFAULT_MAX_TX_SIZE: usize = 100_000;
+pub const DEFAULT_MAX_INPUTS: usize = 250;
+pub const DEFAULT_MAX_OUTPUTS: usize = 250;
+pub const DEFAULT_MIN_RELAY_FEE_RATE: u64 = 100;
+pub const DEFAULT_MAX_DATA_CARRIER_SIZE: usize = 80;
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 50_000_000;
+pub const DEFAULT_MAX_ANCESTORS: usize = 25;
+pub const DEFAULT_MAX_ANCESTOR_SIZE: usize = 101_000;
+pub const DEFAULT_MAX_DESCENDANTS: usize = 25;
+pub const DEFAULT_MAX_DESCENDANT_SIZE: usize = 101_000;
+pub const DEFAULT_REPLACEMENT_FEE_INCREMENT: u64 = 100;
+pub const DEFAULT_MEMPOOL_EXPIRY_SECONDS: u64 = 14 * 24 * 60 * 60;
+pub const DEFAULT_MAX_PENDING_PER_SENDER: usize = 100;
+pub const DEFAULT_STANDARD_SCRIPTS: [&str; 4] = ["pay-to-address", "hash-time-lock", "two-of-two", "data-carrier"];
+
+/// Relay and mempool rules, stricter than the consensus rules, that this node applies to
+/// loose transactions. Blocks are only checked against the consensus rules, so a transaction
+/// breaking this policy is still accepted inside a valid block.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// The smallest value of an output, except data carriers
+    pub dust_threshold: u64,
+    /// The largest serialized size of a transaction, in bytes
+    pub max_tx_size: usize,
+    pub max_inputs: usize,
+    pub max_outputs: usize,
+    /// The smallest fee per 1000 serialized bytes
+    pub min_relay_fee_rate: u64,
+    /// The largest total payload of the data-carrier outputs of a transaction, in bytes
+    pub max_data_carrier_size: usize,
+    /// The largest total serialized size of the transactions in the mempool, in bytes
+    pub max_mempool_size: usize,
+    /// The largest number of unconfirmed transactions a mempool transaction may depend on, itself included
+    pub max_ancestors: usize,
+    /// The largest total size of a mempool transaction and its unconfirmed ancestors, in bytes
+    pub max_ancestor_size: usize,
+    /// The largest number of mempool transactions depending on a transaction, itself included
+    pub max_descendants: usize,
+    /// The largest total size of a mempool transaction and its descendants, in bytes
+    pub max_descendant_size: usize,
+    /// Whether a transaction conflicting with mempool transactions may replace them by paying more
+    pub replace_by_fee: bool,
+    /// The fee per 1000 bytes a replacement must add, both on top of the fees of the transactions
+    /// it replaces and to the fee rate of those it conflicts with
+    pub replacement_fee_increment: u64,
+    /// How long a transaction may stay unconfirmed in the mempool
+    pub mempool_expiry_seconds: u64,
+    /// The largest number of mempool transactions signed by the same address
+    pub max_pending_per_sender: usize,
+    /// The kinds of scripts (see `Script::kind`) allowed in outputs
+    pub standard_scripts: Vec<String>,
+}
+
+/// Why a transaction breaks the policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    TooLarge { size: usize },
+    TooManyInputs { count: usize },
+    TooManyOutputs { count: usize },
+    DustOutput { value: u64 },
+    FeeRateTooLow { fee: u64, size: usize },
+    NonStandardScript { kind: &'static str },
+    DataCarrierTooLarge { size: usize },
+    /// A data carrier is unspendable, so its value would be burned
+    DataCarrierValue { value: u64 },
+    TooManyAncestors { count: usize },
+    AncestorsTooLarge { size: usize },
+    TooManyDescendants { count: usize },
+    DescendantsTooLarge { size: usize },
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PolicyViolation::TooLarge { size } => write!(f, "transaction too large ({} bytes)", size),
+            PolicyViolation::TooManyInputs { count } => write!(f, "too many inputs ({})", count),
+            PolicyViolation::TooManyOutputs { count } => write!(f, "too many outputs ({})", count),
+            PolicyViolation::DustOutput { value } => write!(f, "dust output ({})", value),
+            PolicyViolation::FeeRateTooLow { fee, size } => write!(f, "fee {} too low for {} bytes", fee, size),
+            PolicyViolation::NonStandardScript { kind } => write!(f, "non-standard script {}", kind),
+            PolicyViolation::DataCarrierTooLarge { size } => write!(f, "data carrier too large ({} bytes)", size),
+            PolicyViolation::DataCarrierValue { value } => write!(f, "data carrier burns value {}", value),
+            PolicyViolation::TooManyAncestors { count } => write!(f, "too many unconfirmed ancestors ({})", count),
+            PolicyViolation::AncestorsTooLarge { size } => write!(f, "unconfirmed ancestors too large ({} bytes)", size),
+            PolicyViolation::TooManyDescendants { count } => write!(f, "too many descendants ({})", count),
+            PolicyViolation::DescendantsTooLarge { size } => write!(f, "descendants too large ({} bytes)", size),
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            dust_threshold: DEFAULT_DUST_THRESHOLD,
+            max_tx_size: DEFAULT_MAX_TX_SIZE,
+            max_inputs: DEFAULT_MAX_INPUTS,
+            max_outputs: DEFAULT_MAX_OUTPUTS,
+            min_relay_fee_rate: DEFAULT_MIN_RELAY_FEE_RATE,
+            max_data_carrier_size: DEFAULT_MAX_DATA_CARRIER_SIZE,
+            max_mempool_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            max_ancestors: DEFAULT_MAX_ANCESTORS,
+            max_ancestor_size: DEFAULT_MAX_ANCESTOR_SIZE,
+            max_descendants: DEFAULT_MAX_DESCENDANTS,
+            max_descendant_size: DEFAULT_MAX_DESCENDANT_SIZE,
+            replace_by_fee: false,
+            replacement_fee_increment: DEFAULT_REPLACEMENT_FEE_INCREMENT,
+            mempool_expiry_seconds: DEFAULT_MEMPOOL_EXPIRY_SECONDS,
+            max_pending_per_sender: DEFAULT_MAX_PENDING_PER_SENDER,
+            standard_scripts: DEFAULT_STANDARD_SCRIPTS.iter().map(|kind| kind.to_string()).collect(),
+        }
+    }
+}
+
+impl Policy {
+    /// Check a consensus-valid transaction paying `fee` against the policy
+    pub fn check(&self, tx: &SignedTransaction, fee: u64) -> Result<(), PolicyViolation> {
+        let size = tx.size();
+        if size > self.max_tx_size {
+            return Err(PolicyViolation::TooLarge { size });
+        }
+        let inputs = tx.raw.TransactionInput.len();
+        if inputs > self.max_inputs {
+            return Err(PolicyViolation::TooManyInputs { count: inputs });
+        }
+        let outputs = tx.raw.TransactionOutput.len();
+        if outputs > self.max_outputs {
+            return Err(PolicyViolation::TooManyOutputs { count: outputs });
+        }
+        for output in &tx.raw.TransactionOutput {
+            let kind = output.script.kind();
+            if !self.standard_scripts.iter().any(|standard| standard == kind) {
+                return Err(PolicyViolation::NonStandardScript { kind });
+            }
+            if let Script::DataCarrier(_) = output.script {
+                if output.value > 0 {
+                    return Err(PolicyViolation::DataCarrierValue { value: output.value });
+                }
+                continue;
+            }
+            if output.value < self.dust_threshold {
+                return Err(PolicyViolation::DustOutput { value: output.value });
+            }
+        }
+        let data_size: usize = tx.raw.TransactionOutput.iter()
+            .map(|output| match &output.script {
+                Script::DataCarrier(data) => data.len(),
+                _ => 0,
+            })
+            .sum();
+        if data_size > self.max_data_carrier_size {
+            return Err(PolicyViolation::DataCarrierTooLarge { size: data_size });
+        }
+        if (fee as u128) * 1000 < (self.min_relay_fee_rate as u128) * (size as u128) {
+            return Err(PolicyViolation::FeeRateTooLow { fee, size });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::H160;
+    use crate::crypto::key_pair;
+    use crate::transaction::{Transaction, TransactionOutput};
+
+    #[test]
+    fn standardness() {
+        let policy = Policy::default();
+        let output = TransactionOutput { recipient: H160::from([1u8; 20]), value: 1_000, script: Script::PayToAddress };
+        let raw = Transaction { TransactionInput: vec![], TransactionOutput: vec![output] };
+        let tx = SignedTransaction::from_raw(raw.clone(), &key_pair::random());
+        assert_eq!(policy.check(&tx, 100), Ok(()));
+        assert_eq!(policy.check(&tx, 0), Err(PolicyViolation::FeeRateTooLow { fee: 0, size: tx.size() }));
+
+        let mut dust = raw.clone();
+        dust.TransactionOutput[0].value = DEFAULT_DUST_THRESHOLD - 1;
+        let tx = SignedTransaction::from_raw(dust, &key_pair::random());
+        assert_eq!(policy.check(&tx, 100), Err(PolicyViolation::DustOutput { value: DEFAULT_DUST_THRESHOLD - 1 }));
+
+        let mut data = raw.clone();
+        data.TransactionOutput.push(TransactionOutput {
+            recipient: H160::default(),
+            value: 0,
+            script: Script::DataCarrier(vec![0; DEFAULT_MAX_DATA_CARRIER_SIZE]),
+        });
+        let tx = SignedTransaction::from_raw(data.clone(), &key_pair::random());
+        assert_eq!(policy.check(&tx, 1_000), Ok(()));
+        let mut burning = data.clone();
+        burning.TransactionOutput[1].value = 1;
+        let tx = SignedTransaction::from_raw(burning, &key_pair::random());
+        assert_eq!(policy.check(&tx, 1_000), Err(PolicyViolation::DataCarrierValue { value: 1 }));
+        data.TransactionOutput[1].script = Script::DataCarrier(vec![0; DEFAULT_MAX_DATA_CARRIER_SIZE + 1]);
+        let tx = SignedTransaction::from_raw(data, &key_pair::random());
+        assert_eq!(policy.check(&tx, 1_000), Err(PolicyViolation::DataCarrierTooLarge { size: DEFAULT_MAX_DATA_CARRIER_SIZE + 1 }));
+
+        let strict = Policy { standard_scripts: vec![], ..Policy::default() };
+        let tx = SignedTransaction::from_raw(raw, &key_pair::random());
+        assert_eq!(strict.check(&tx, 100), Err(PolicyViolation::NonStandardScript { kind: "pay-to-address" }));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::address::H160;
+use crate::crypto::hash::H256;
+
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_POOL_WINDOW: usize = 100;
+
+/// A mining pool coordinator. Workers prove their work with shares, i.e. solutions meeting a
+/// target `share_factor` times easier than the block target, credited to their address.
+/// Each block pays the last `window` shares ("pay per last N shares"): the coinbase splits the
+/// reward among the addresses in proportion to their shares in the window.
+pub struct Pool {
+    share_factor: u64,
+    window: usize,
+    /// The addresses credited with the last `window` shares, oldest first
+    recent_shares: VecDeque<H160>,
+    /// The number of shares accepted from each address
+    accepted: HashMap<H160, u64>,
+}
+
+/// The shares accepted from each address, and how the next block would be paid
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PoolStats {
+    pub accepted: Vec<(H160, u64)>,
+    pub payouts: Vec<(H160, u64)>,
+}
+
+/// Multiply a big-endian target by `factor`, saturating at the easiest target
+fn scale_target(target: &H256, factor: u64) -> H256 {
+    let mut bytes: [u8; 32] = target.into();
+    let mut carry: u128 = 0;
+    for byte in bytes.iter_mut().rev() {
+        let product = *byte as u128 * factor as u128 + carry;
+        *byte = product as u8;
+        carry = product >> 8;
+    }
+    if carry > 0 {
+        return [0xffu8; 32].into();
+    }
+    bytes.into()
+}
+
+impl Pool {
+    pub fn new(share_factor: u64, window: usize) -> Self {
+        Pool {
+            share_factor,
+            window,
+            recent_shares: VecDeque::new(),
+            accepted: HashMap::new(),
+        }
+    }
+
+    /// The target of a share on a block with target `block_target`
+    pub fn share_target(&self, block_target: &H256) -> H256 {
+        scale_target(block_target, self.share_factor)
+    }
+
+    /// Credit a share to `worker`
+    pub fn add_share(&mut self, worker: H160) {
+        *self.accepted.entry(worker).or_default() += 1;
+        self.recent_shares.push_back(worker);
+        while self.recent_shares.len() > self.window {
+            self.recent_shares.pop_front();
+        }
+    }
+
+    /// The addresses to pay in the next block, weighted by their shares in the window,
+    /// in the order of their first share in the window
+    pub fn payouts(&self) -> Vec<(H160, u64)> {
+        let mut payouts: Vec<(H160, u64)> = vec![];
+        for worker in &self.recent_shares {
+            match payouts.iter_mut().find(|(address, _)| address == worker) {
+                Some((_, weight)) => *weight += 1,
+                None => payouts.push((*worker, 1)),
+            }
+        }
+        payouts
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            accepted: self.accepted.iter().map(|(address, count)| (*address, *count)).collect(),
+            payouts: self.payouts(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_and_payouts() {
+        let mut target = [0u8; 32];
+        target[0] = 1;
+        let mut share_target = [0u8; 32];
+        share_target[0] = 4;
+        let mut pool = Pool::new(4, 3);
+        assert_eq!(pool.share_target(&target.into()), share_target.into());
+        assert_eq!(pool.share_target(&[0x80u8; 32].into()), [0xffu8; 32].into());
+
+        let (a, b) = (H160::from([1u8; 20]), H160::from([2u8; 20]));
+        pool.add_share(a);
+        pool.add_share(b);
+        pool.add_share(a);
+        assert_eq!(pool.payouts(), vec![(a, 2), (b, 1)]);
+        // the oldest share leaves the window:
+        pool.add_share(b);
+        assert_eq!(pool.payouts(), vec![(b, 2), (a, 1)]);
+        assert_eq!(pool.stats().accepted.iter().find(|(address, _)| *address == a).unwrap().1, 2);
+    }
+}
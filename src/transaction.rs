@@ -11,10 +11,50 @@ pub struct TransactionInput {
     pub prev_tx: H256,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone, Hash, PartialEq, Eq, Copy)]
+/// The most data a `Script::DataCarrier` output can carry
+pub const MAX_DATA_CARRIER_SIZE: usize = 1024;
+
+/// The condition under which an output can be spent
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Hash, PartialEq, Eq)]
+pub enum Script {
+    /// Spendable by the owner of `recipient`
+    #[default]
+    PayToAddress,
+    /// Spendable by `recipient` with a preimage of `hash_lock` before block height `timeout`,
+    /// and by `refund` from that height on
+    HashTimeLock {
+        hash_lock: H256,
+        refund: H160,
+        timeout: u64,
+    },
+    /// Spendable by `recipient` and `cosigner` together before block height `timeout`,
+    /// and by `recipient` alone from that height on
+    TwoOfTwo {
+        cosigner: H160,
+        timeout: u64,
+    },
+    /// Provably unspendable, carrying up to `MAX_DATA_CARRIER_SIZE` bytes of arbitrary data
+    /// (e.g. the hash of an artifact to timestamp). It never enters the state.
+    DataCarrier(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Hash, PartialEq, Eq)]
 pub struct TransactionOutput {
     pub recipient: H160,
-    pub value: H256,
+    pub value: u64,
+    pub script: Script,
+}
+
+/// Extra data revealed by a transaction to unlock script-locked inputs. It is not covered by the signature.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Witness {
+    /// The preimage of a `Script::HashTimeLock` hash lock
+    Preimage(Vec<u8>),
+    /// A signature of the raw transaction by a second key, e.g. the cosigner of a `Script::TwoOfTwo` output
+    CoSignature {
+        pub_key: Vec<u8>,
+        signature: Vec<u8>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, Hash)]
@@ -31,6 +71,42 @@ pub struct SignedTransaction {
     pub raw: Transaction,  
     pub pub_key: Vec<u8>,
     pub signature: Vec<u8>,
+    pub witness: Vec<Witness>,
+}
+
+impl Script {
+    /// The name of this kind of script, e.g. for listing the standard scripts of a `Policy`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Script::PayToAddress => "pay-to-address",
+            Script::HashTimeLock { .. } => "hash-time-lock",
+            Script::TwoOfTwo { .. } => "two-of-two",
+            Script::DataCarrier(_) => "data-carrier",
+        }
+    }
+}
+
+impl TransactionOutput {
+    /// Check if a transaction signed by `signers` may spend this output in a block at `height`, revealing `witness`
+    pub fn can_spend(&self, signers: &[H160], witness: &[Witness], height: u64) -> bool {
+        match self.script {
+            Script::PayToAddress => signers.contains(&self.recipient),
+            Script::DataCarrier(_) => false,
+            Script::HashTimeLock { ref hash_lock, ref refund, timeout } => {
+                if height < timeout {
+                    signers.contains(&self.recipient) && witness.iter().any(|w| match w {
+                        Witness::Preimage(preimage) => hash_preimage(preimage) == *hash_lock,
+                        _ => false,
+                    })
+                } else {
+                    signers.contains(refund)
+                }
+            }
+            Script::TwoOfTwo { ref cosigner, timeout } => {
+                signers.contains(&self.recipient) && (height >= timeout || signers.contains(cosigner))
+            }
+        }
+    }
 }
 
 
@@ -40,7 +116,55 @@ impl SignedTransaction {
     pub fn from_raw(raw: Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
         let pub_key = key.public_key().as_ref().to_vec();
         let signature = sign(&raw, key).as_ref().to_vec();
-        SignedTransaction { raw, pub_key, signature }
+        SignedTransaction { raw, pub_key, signature, witness: vec![] }
+    }
+
+    /// Create a new transaction that also reveals `witness` to unlock its inputs
+    pub fn from_raw_with_witness(raw: Transaction, key: &Ed25519KeyPair, witness: Vec<Witness>) -> SignedTransaction {
+        SignedTransaction { witness, ..SignedTransaction::from_raw(raw, key) }
+    }
+
+    /// The address of the signer of this transaction
+    pub fn signer(&self) -> H160 {
+        H160::from_pubkey(&self.pub_key)
+    }
+
+    /// Verify the signature of this transaction, and return the addresses of its valid signers
+    /// (`None` if the signature is invalid)
+    pub fn verified_signers(&self) -> Option<Vec<H160>> {
+        if self.verify_signature() {
+            Some(self.signers())
+        } else {
+            None
+        }
+    }
+
+    /// The addresses of the signer and of every co-signer whose signature is valid
+    pub fn signers(&self) -> Vec<H160> {
+        let serialized_raw = bincode::serialize(&self.raw).unwrap();
+        let mut signers = vec![self.signer()];
+        for witness in &self.witness {
+            if let Witness::CoSignature { pub_key, signature } = witness {
+                let public_key = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &pub_key[..]);
+                if public_key.verify(&serialized_raw, signature).is_ok() {
+                    signers.push(H160::from_pubkey(pub_key));
+                }
+            }
+        }
+        signers
+    }
+
+    /// Add the signature of a second key, e.g. to spend a `Script::TwoOfTwo` output
+    pub fn cosign(&mut self, key: &Ed25519KeyPair) {
+        self.witness.push(Witness::CoSignature {
+            pub_key: key.public_key().as_ref().to_vec(),
+            signature: sign(&self.raw, key).as_ref().to_vec(),
+        });
+    }
+
+    /// Obtain the transaction size in bytes
+    pub fn size(&self) -> usize {
+        bincode::serialize(&self).unwrap().len()
     }
 
     /// Verify the signature of this transaction
@@ -66,6 +190,11 @@ impl Hashable for SignedTransaction {
     }
 }
 
+/// Hash a hash lock preimage using SHA256.
+pub fn hash_preimage(preimage: &[u8]) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, preimage).into()
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     key.sign(bincode::serialize(&t).unwrap().as_ref())
@@ -93,7 +222,8 @@ mod tests {
         };
         let output1 = TransactionOutput{
             recipient: H160::from_pubkey(&controlled_keypair.public_key().as_ref()),
-            value: H256::from([10u8; 32]),
+            value: 10,
+            script: Script::PayToAddress,
         };
         let trans = Transaction{
             TransactionInput: vec![trans1],
@@ -109,4 +239,41 @@ mod tests {
         let signature = sign(&t, &key);
         assert!(verify(&t, &(key.public_key()), &signature));
     }
+
+    #[test]
+    fn hash_time_lock() {
+        let recipient = H160::from([1u8; 20]);
+        let refund = H160::from([2u8; 20]);
+        let preimage = vec![42u8; 32];
+        let output = TransactionOutput {
+            recipient,
+            value: 10,
+            script: Script::HashTimeLock { hash_lock: hash_preimage(&preimage), refund, timeout: 10 },
+        };
+        let revealed = vec![Witness::Preimage(preimage)];
+        let wrong = vec![Witness::Preimage(vec![0u8; 32])];
+        assert!(output.can_spend(&[recipient], &revealed, 9));
+        assert!(!output.can_spend(&[recipient], &wrong, 9));
+        assert!(!output.can_spend(&[recipient], &[], 9));
+        assert!(!output.can_spend(&[refund], &revealed, 9));
+        assert!(!output.can_spend(&[recipient], &revealed, 10));
+        assert!(output.can_spend(&[refund], &[], 10));
+    }
+
+    #[test]
+    fn two_of_two() {
+        let payer = key_pair::random();
+        let payee = key_pair::random();
+        let funding = TransactionOutput {
+            recipient: H160::from_pubkey(payer.public_key().as_ref()),
+            value: 10,
+            script: Script::TwoOfTwo { cosigner: H160::from_pubkey(payee.public_key().as_ref()), timeout: 10 },
+        };
+        let mut tx = SignedTransaction::from_raw(generate_random_transaction(), &payer);
+        assert!(!funding.can_spend(&tx.signers(), &tx.witness, 9));
+        assert!(funding.can_spend(&tx.signers(), &tx.witness, 10));
+        tx.cosign(&payee);
+        assert!(tx.verify_signature());
+        assert!(funding.can_spend(&tx.signers(), &tx.witness, 9));
+    }
 }
@@ -0,0 +1,288 @@
+use crate::address::H160;
+use crate::policy::DEFAULT_DUST_THRESHOLD;
+use crate::transaction::{Script, SignedTransaction, State, Transaction, TransactionInput, TransactionOutput};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{FromEntropy, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::cmp::Reverse;
+
+/// The excess over the target that branch-and-bound may leave as extra fee, instead of creating change
+const BNB_MAX_EXCESS: u64 = 1_000;
+/// How many branches branch-and-bound explores before giving up
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// How to choose the unspent outputs that fund a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// Spend the largest outputs first, minimizing the number of inputs
+    LargestFirst,
+    /// Search for a set of outputs matching the target closely enough to need no change output,
+    /// falling back to largest-first
+    BranchAndBound,
+    /// Spend outputs in random order
+    Random,
+}
+
+/// Builds a signed transaction paying some outputs plus a fee, funded by unspent outputs
+/// owned by the given keys, and sending any remainder back as change.
+pub struct TransactionBuilder<'a> {
+    keys: Vec<&'a Ed25519KeyPair>,
+    outputs: Vec<TransactionOutput>,
+    fee: u64,
+    coin_selection: CoinSelection,
+    change_address: Option<H160>,
+    dust_threshold: u64,
+    /// The seed of the random coin selection, random itself if not set
+    seed: Option<u64>,
+}
+
+fn address(key: &Ed25519KeyPair) -> H160 {
+    H160::from_pubkey(key.public_key().as_ref())
+}
+
+/// Take coins in the given order until reaching `target`, failing if their total overflows
+fn select_in_order(coins: &[(TransactionInput, u64)], target: u64) -> Option<Vec<(TransactionInput, u64)>> {
+    let mut total: u64 = 0;
+    let mut selected = vec![];
+    for coin in coins {
+        if total >= target {
+            break;
+        }
+        total = total.checked_add(coin.1)?;
+        selected.push(*coin);
+    }
+    if total >= target {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// Depth-first search for a subset of `coins` (sorted by decreasing value) whose total is within
+/// `[target, target + BNB_MAX_EXCESS]`
+fn branch_and_bound(coins: &[(TransactionInput, u64)], target: u64) -> Option<Vec<(TransactionInput, u64)>> {
+    // remaining[i] is the total value of coins[i..], saturated as it only bounds the search
+    let mut remaining = vec![0u64; coins.len() + 1];
+    for i in (0..coins.len()).rev() {
+        remaining[i] = remaining[i + 1].saturating_add(coins[i].1);
+    }
+    let mut included = vec![false; coins.len()];
+    let mut tries = 0;
+
+    fn search(
+        coins: &[(TransactionInput, u64)],
+        remaining: &[u64],
+        included: &mut Vec<bool>,
+        depth: usize,
+        total: u64,
+        target: u64,
+        tries: &mut usize,
+    ) -> bool {
+        *tries += 1;
+        if total > target.saturating_add(BNB_MAX_EXCESS) || *tries > BNB_MAX_TRIES {
+            return false;
+        }
+        if total >= target {
+            return true;
+        }
+        if depth == coins.len() || total.saturating_add(remaining[depth]) < target {
+            return false;
+        }
+        // a coin overflowing the total is too large to include:
+        if let Some(total) = total.checked_add(coins[depth].1) {
+            included[depth] = true;
+            if search(coins, remaining, included, depth + 1, total, target, tries) {
+                return true;
+            }
+        }
+        included[depth] = false;
+        search(coins, remaining, included, depth + 1, total, target, tries)
+    }
+
+    if search(coins, &remaining, &mut included, 0, 0, target, &mut tries) {
+        Some(coins.iter().zip(included).filter(|(_, included)| *included).map(|(coin, _)| *coin).collect())
+    } else {
+        None
+    }
+}
+
+impl<'a> TransactionBuilder<'a> {
+    /// Create a builder spending outputs owned by `key`
+    pub fn new(key: &'a Ed25519KeyPair) -> Self {
+        TransactionBuilder {
+            keys: vec![key],
+            outputs: vec![],
+            fee: 0,
+            coin_selection: CoinSelection::LargestFirst,
+            change_address: None,
+            dust_threshold: DEFAULT_DUST_THRESHOLD,
+            seed: None,
+        }
+    }
+
+    /// Also spend outputs owned by `key`, which then co-signs the transaction
+    pub fn key(mut self, key: &'a Ed25519KeyPair) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// Pay `value` to `recipient`
+    pub fn pay(self, recipient: H160, value: u64) -> Self {
+        self.output(TransactionOutput { recipient, value, script: Script::PayToAddress })
+    }
+
+    /// Add an arbitrary output
+    pub fn output(mut self, output: TransactionOutput) -> Self {
+        self.outputs.push(output);
+        self
+    }
+
+    /// Attach `data` in an unspendable data-carrier output
+    pub fn data(self, data: Vec<u8>) -> Self {
+        self.output(TransactionOutput { recipient: H160::default(), value: 0, script: Script::DataCarrier(data) })
+    }
+
+    /// Leave `fee` to the miner, on top of the outputs
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn coin_selection(mut self, coin_selection: CoinSelection) -> Self {
+        self.coin_selection = coin_selection;
+        self
+    }
+
+    /// Send the change to `address` instead of the address of the first key
+    pub fn change_address(mut self, address: H160) -> Self {
+        self.change_address = Some(address);
+        self
+    }
+
+    /// Leave change smaller than `dust_threshold` as extra fee instead of creating a dust output
+    pub fn dust_threshold(mut self, dust_threshold: u64) -> Self {
+        self.dust_threshold = dust_threshold;
+        self
+    }
+
+    /// Make the random coin selection reproducible
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Select inputs from `state`, add a change output if needed, and sign the transaction
+    /// with the keys owning the selected inputs
+    pub fn build(self, state: &State) -> Result<SignedTransaction, String> {
+        let owners: Vec<H160> = self.keys.iter().map(|key| address(key)).collect();
+        let target = self.outputs.iter()
+            .try_fold(self.fee, |sum, output| sum.checked_add(output.value))
+            .ok_or("output values overflow")?;
+
+        let mut coins: Vec<(TransactionInput, u64)> = state.iter()
+            .filter(|(_, output)| output.script == Script::PayToAddress && owners.contains(&output.recipient))
+            .map(|(input, output)| (*input, output.value))
+            .collect();
+        // sort for a deterministic order, as the state is a hash map
+        coins.sort_by_key(|(input, value)| (Reverse(*value), input.prev_tx, input.txid));
+        let selected = match self.coin_selection {
+            CoinSelection::LargestFirst => select_in_order(&coins, target),
+            CoinSelection::BranchAndBound => {
+                branch_and_bound(&coins, target).or_else(|| select_in_order(&coins, target))
+            }
+            CoinSelection::Random => {
+                let mut rng = self.seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+                coins.shuffle(&mut rng);
+                select_in_order(&coins, target)
+            }
+        };
+        let selected = selected.ok_or_else(|| {
+            let available = coins.iter().fold(0u64, |sum, (_, value)| sum.saturating_add(*value));
+            format!("insufficient funds: {} < {}", available, target)
+        })?;
+
+        let total: u64 = selected.iter().map(|(_, value)| value).sum();
+        let mut outputs = self.outputs;
+        let excess = total - target;
+        let within_bnb_excess = self.coin_selection == CoinSelection::BranchAndBound && excess <= BNB_MAX_EXCESS;
+        if excess >= self.dust_threshold && excess > 0 && !within_bnb_excess {
+            outputs.push(TransactionOutput {
+                recipient: self.change_address.unwrap_or(owners[0]),
+                value: excess,
+                script: Script::PayToAddress,
+            });
+        }
+        let inputs: Vec<TransactionInput> = selected.into_iter().map(|(input, _)| input).collect();
+
+        // the first key owning an input signs, the other owners co-sign:
+        let signing_keys: Vec<&Ed25519KeyPair> = self.keys.iter().zip(&owners)
+            .filter(|(_, owner)| inputs.iter().any(|input| state[input].recipient == **owner))
+            .map(|(key, _)| *key)
+            .collect();
+        let raw = Transaction { TransactionInput: inputs, TransactionOutput: outputs };
+        let mut tx = SignedTransaction::from_raw(raw, signing_keys.first().copied().unwrap_or(self.keys[0]));
+        for key in signing_keys.iter().skip(1) {
+            tx.cosign(key);
+        }
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::crypto::hash::H256;
+    use crate::crypto::key_pair;
+
+    fn state_with_values(owner: H160, values: &[u64]) -> State {
+        values.iter().enumerate().map(|(i, value)| {
+            (TransactionInput { txid: i as u32, prev_tx: H256::from([1u8; 32]) },
+             TransactionOutput { recipient: owner, value: *value, script: Script::PayToAddress })
+        }).collect()
+    }
+
+    #[test]
+    fn build_with_change() {
+        let key = key_pair::random();
+        let state = state_with_values(address(&key), &[50_000, 30_000, 15_500]);
+        let recipient = H160::from([9u8; 20]);
+        for coin_selection in &[CoinSelection::LargestFirst, CoinSelection::BranchAndBound, CoinSelection::Random] {
+            let tx = TransactionBuilder::new(&key)
+                .pay(recipient, 45_000)
+                .fee(100)
+                .coin_selection(*coin_selection)
+                .build(&state)
+                .unwrap();
+            assert!(Blockchain::transaction_check(&tx, &state, 1));
+            let input_value: u64 = tx.raw.TransactionInput.iter().map(|input| state[input].value).sum();
+            let output_value: u64 = tx.raw.TransactionOutput.iter().map(|output| output.value).sum();
+            assert_eq!(tx.raw.TransactionOutput[0].recipient, recipient);
+            if *coin_selection == CoinSelection::BranchAndBound {
+                // 30_000 + 15_500 is close enough to 45_100 to need no change
+                assert_eq!(tx.raw.TransactionOutput.len(), 1);
+                assert!(input_value - output_value - 100 <= BNB_MAX_EXCESS);
+            } else {
+                assert_eq!(input_value - output_value, 100);
+            }
+        }
+        assert!(TransactionBuilder::new(&key).pay(recipient, 95_501).build(&state).is_err());
+    }
+
+    #[test]
+    fn huge_target() {
+        let key = key_pair::random();
+        let recipient = H160::from([9u8; 20]);
+        for values in &[vec![50_000, 30_000], vec![u64::MAX - 100, u64::MAX - 100]] {
+            let state = state_with_values(address(&key), values);
+            for coin_selection in &[CoinSelection::LargestFirst, CoinSelection::BranchAndBound, CoinSelection::Random] {
+                let result = TransactionBuilder::new(&key)
+                    .pay(recipient, u64::MAX - 10)
+                    .coin_selection(*coin_selection)
+                    .build(&state);
+                assert!(result.unwrap_err().starts_with("insufficient funds"));
+            }
+        }
+    }
+}
@@ -1,24 +1,31 @@
 use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::H256;
 
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
 use std::time;
 use std::sync::{Arc, Mutex};
-use crate::mempool::Mempool;
+use crate::mempool::{Mempool, RejectReason};
 use crate::network::message::Message;
-use crate::transaction::{SignedTransaction, Transaction, TransactionInput, TransactionOutput, State};
 use crate::blockchain::{Blockchain};
 use crate::address::H160;
+use crate::clock::Clock;
+use crate::transaction_builder::{CoinSelection, TransactionBuilder};
+use crate::verifier::Handle as VerifierHandle;
+use crate::policy::DEFAULT_DUST_THRESHOLD;
 use rand::prelude::*;
-use crate::crypto::key_pair;
+use rand::rngs::StdRng;
 
 pub struct TransactionGenerator {
     server: ServerHandle,
     mempool: Arc<Mutex<Mempool>>,
+    verifier: VerifierHandle,
     pub blockchain: Arc<Mutex<Blockchain>>,
     pub controlled_keypair: Ed25519KeyPair,
+    rng: StdRng,
+    /// The clock the transactions are generated by
+    clock: Arc<dyn Clock>,
 }
 
 impl TransactionGenerator {
@@ -26,17 +33,23 @@ impl TransactionGenerator {
         server: &ServerHandle,
         mempool: &Arc<Mutex<Mempool>>,
         blockchain: &Arc<Mutex<Blockchain>>,
-        controlled_keypair: Ed25519KeyPair
+        verifier: &VerifierHandle,
+        controlled_keypair: Ed25519KeyPair,
+        rng: StdRng,
+        clock: &Arc<dyn Clock>,
     ) -> TransactionGenerator {
         TransactionGenerator {
             server: server.clone(),
             mempool: Arc::clone(mempool),
+            verifier: verifier.clone(),
             blockchain: Arc::clone(blockchain),
             controlled_keypair,
+            rng,
+            clock: Arc::clone(clock),
         }
     }
 
-    pub fn start(self) {
+    pub fn start(mut self) {
         thread::spawn(move || {
             self.generation_loop();
             log::warn!("Transaction Generator exited");
@@ -44,49 +57,66 @@ impl TransactionGenerator {
     }
 
     /// Generate random transactions and send them to the server
-    fn generation_loop(&self) {
+    fn generation_loop(&mut self) {
         const INTERVAL_MILLISECONDS: u64 = 3000; // how quickly to generate transactions
+        const MAX_VALUE: u64 = 10_000; // the largest value to pay in one transaction
+        const FEE: u64 = 100;
+        const ACCOUNT_INDICES: [u8; 3] = [0, 100, 200]; // the pre-set keypairs to pay
+        const CLOCK_CHECK_MILLISECONDS: u128 = 100; // how often to check the clock while waiting
 
+        let mut next_ms = self.clock.now_ms() + INTERVAL_MILLISECONDS as u128;
         loop {
-            // sleep for some time:
-            let interval = time::Duration::from_millis(INTERVAL_MILLISECONDS);
-            thread::sleep(interval);
-
-            let mut mempool = self.mempool.lock().unwrap();
-
-            let keypair = &self.controlled_keypair;
+            // wait until the clock reaches the next generation time, which need not be the wall clock's:
+            let now_ms = self.clock.now_ms();
+            if now_ms < next_ms {
+                let wait_ms = (next_ms - now_ms).min(CLOCK_CHECK_MILLISECONDS);
+                thread::sleep(time::Duration::from_millis(wait_ms as u64));
+                continue;
+            }
+            next_ms = now_ms + INTERVAL_MILLISECONDS as u128;
 
-            //read the state and check which UTXO to use
-            let blockchain = self.blockchain.lock().unwrap();
-            let tip = blockchain.tip();
-            let state_to_read = blockchain.get_state(&tip);
-            let mut state_to_use = state_to_read.clone();
-            let mut input_vec: Vec<TransactionInput> = vec![];
-            let mut output_vec: Vec<TransactionOutput> = vec![];
-            for  (tx_input, tx_output) in state_to_read.iter() {
-                if tx_output.recipient != H160::from_pubkey(&keypair.public_key().as_ref()){
-                    state_to_use.remove(tx_input);
-                    continue;
-                } else{
-                    input_vec.push(tx_input.clone());
-                    output_vec.push(tx_output.clone());
+            // 1. generate a random payment to one of the pre-set keypairs, funded by our own UTXOs
+            // not spent by pending transactions, including the change of those:
+            let index = *ACCOUNT_INDICES.choose(&mut self.rng).unwrap();
+            let recipient_keypair = Ed25519KeyPair::from_seed_unchecked(&[index; 32]).unwrap();
+            let recipient = H160::from_pubkey(recipient_keypair.public_key().as_ref());
+            let value = self.rng.gen_range(DEFAULT_DUST_THRESHOLD, MAX_VALUE + 1);
+            let signed_trans = {
+                // lock the blockchain before the mempool, like everywhere else:
+                let blockchain = self.blockchain.lock().unwrap();
+                let mempool = self.mempool.lock().unwrap();
+                match TransactionBuilder::new(&self.controlled_keypair)
+                    .pay(recipient, value)
+                    .fee(FEE)
+                    .coin_selection(CoinSelection::Random)
+                    .seed(self.rng.gen())
+                    .build(&mempool.state(&blockchain)) {
+                    Ok(tx) => tx,
+                    Err(e) => {
+                        log::debug!("Cannot generate a transaction: {}", e);
+                        continue;
+                    }
                 }
-            }
-              
-            // 1. generate some random transactions:
-            let trans = Transaction{
-                TransactionInput: input_vec,
-                TransactionOutput: output_vec,
             };
-            let signed_trans = SignedTransaction::from_raw(trans, &key_pair::random()).clone();
 
-            // 2. add these transactions to the mempool:
+            // 2. add these transactions to the mempool, verifying the signature before taking the locks:
             let mut t_hash: Vec<H256> = vec![];
-            mempool.insert(signed_trans.clone());
-            let transaction_hash = &signed_trans.hash();
-            t_hash.push(transaction_hash.clone());
+            let result = match self.verifier.verify_one(&signed_trans) {
+                Some(signers) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    self.mempool.lock().unwrap().accept_verified(signed_trans, &signers, &blockchain)
+                }
+                None => Err(RejectReason::Invalid),
+            };
+            match result {
+                Ok(transaction_hash) => t_hash.push(transaction_hash),
+                Err(reason) => {
+                    log::warn!("Generated a rejected transaction: {}", reason);
+                    continue;
+                }
+            }
             // 3. broadcast them using `self.server.broadcast(Message::NewTransactionHashes(...))`:
             self.server.broadcast(Message::NewTransactionHashes(t_hash));
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,180 @@
+use crate::address::H160;
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::SignedTransaction;
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many transactions the signature cache remembers
+const CACHE_SIZE: usize = 100_000;
+
+/// The valid signers of a transaction, or `None` if its signature is invalid
+pub type Signers = Option<Vec<H160>>;
+
+/// A chunk of a batch to verify on one thread, each transaction tagged with its index in the batch
+struct Job {
+    transactions: Vec<(usize, SignedTransaction)>,
+    result_chan: Sender<Vec<(usize, Signers)>>,
+}
+
+/// The valid signers of recently verified transactions, by transaction hash.
+/// The oldest entries are evicted first once full.
+struct SignatureCache {
+    signers: HashMap<H256, Vec<H160>>,
+    order: VecDeque<H256>,
+}
+
+impl SignatureCache {
+    fn insert(&mut self, hash: H256, signers: Vec<H160>) {
+        if self.signers.insert(hash, signers).is_none() {
+            self.order.push_back(hash);
+        }
+        while self.order.len() > CACHE_SIZE {
+            let oldest = self.order.pop_front().unwrap();
+            self.signers.remove(&oldest);
+        }
+    }
+}
+
+pub struct Context {
+    job_chan: Receiver<Job>,
+    num_worker: usize,
+}
+
+/// Verifies the signatures of batches of transactions in parallel on a pool of threads,
+/// so that it can be done before taking the blockchain and mempool locks
+#[derive(Clone)]
+pub struct Handle {
+    job_chan: Sender<Job>,
+    num_worker: usize,
+    cache: Arc<Mutex<SignatureCache>>,
+}
+
+pub fn new(num_worker: usize) -> (Context, Handle) {
+    let (job_sender, job_receiver) = unbounded();
+    let ctx = Context {
+        job_chan: job_receiver,
+        num_worker,
+    };
+    let handle = Handle {
+        job_chan: job_sender,
+        num_worker,
+        cache: Arc::new(Mutex::new(SignatureCache {
+            signers: HashMap::new(),
+            order: VecDeque::new(),
+        })),
+    };
+    (ctx, handle)
+}
+
+impl Context {
+    pub fn start(self) {
+        for i in 0..self.num_worker {
+            let job_chan = self.job_chan.clone();
+            thread::Builder::new()
+                .name(format!("verifier-{}", i))
+                .spawn(move || {
+                    for job in job_chan.iter() {
+                        let results = job.transactions.into_iter()
+                            .map(|(index, tx)| (index, tx.verified_signers()))
+                            .collect();
+                        job.result_chan.send(results).unwrap();
+                    }
+                    warn!("Verifier thread {} exited", i);
+                })
+                .unwrap();
+        }
+    }
+}
+
+impl Handle {
+    /// Verify the signatures of `transactions`, returning the valid signers of each.
+    /// Transactions verified before (e.g. when they entered the mempool) are not verified again.
+    pub fn verify(&self, transactions: &[SignedTransaction]) -> Vec<Signers> {
+        let hashes: Vec<H256> = transactions.iter().map(|tx| tx.hash()).collect();
+        let mut results: Vec<Signers> = {
+            let cache = self.cache.lock().unwrap();
+            hashes.iter().map(|hash| cache.signers.get(hash).cloned()).collect()
+        };
+        let misses: Vec<(usize, SignedTransaction)> = results.iter().enumerate()
+            .filter(|(_, signers)| signers.is_none())
+            .map(|(index, _)| (index, transactions[index].clone()))
+            .collect();
+        if misses.is_empty() {
+            return results;
+        }
+
+        // split the misses evenly among the threads:
+        let (result_sender, result_receiver) = unbounded();
+        let chunk_size = misses.len().div_ceil(self.num_worker);
+        let chunks: Vec<_> = misses.chunks(chunk_size).collect();
+        for chunk in &chunks {
+            self.job_chan.send(Job {
+                transactions: chunk.to_vec(),
+                result_chan: result_sender.clone(),
+            }).unwrap();
+        }
+        for _ in 0..chunks.len() {
+            for (index, signers) in result_receiver.recv().unwrap() {
+                results[index] = signers;
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        for (index, _) in misses {
+            if let Some(signers) = &results[index] {
+                cache.insert(hashes[index], signers.clone());
+            }
+        }
+        results
+    }
+
+    /// Verify the signature of a single transaction, e.g. one submitted locally, so that it is not
+    /// verified again when it arrives in a block
+    pub fn verify_one(&self, transaction: &SignedTransaction) -> Signers {
+        self.verify(std::slice::from_ref(transaction)).pop().unwrap()
+    }
+
+    /// Verify the signatures of all transactions of `blocks` as one batch,
+    /// returning the valid signers of each transaction of each block
+    pub fn verify_blocks(&self, blocks: &[Block]) -> Vec<Vec<Signers>> {
+        let transactions: Vec<SignedTransaction> = blocks.iter()
+            .flat_map(|block| block.content.transactions.iter().cloned())
+            .collect();
+        let mut results = self.verify(&transactions).into_iter();
+        blocks.iter()
+            .map(|block| results.by_ref().take(block.content.transactions.len()).collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn verify_batch() {
+        let (ctx, verifier) = new(2);
+        ctx.start();
+        let key = key_pair::random();
+        let valid = SignedTransaction::from_raw(Transaction::default(), &key);
+        let mut invalid = valid.clone();
+        invalid.signature[0] ^= 1;
+        let results = verifier.verify(&[valid.clone(), invalid.clone(), valid.clone()]);
+        assert!(results[0].is_some());
+        assert!(results[1].is_none());
+        assert_eq!(results[0], results[2]);
+        assert!(verifier.cache.lock().unwrap().signers.contains_key(&valid.hash()));
+        assert!(!verifier.cache.lock().unwrap().signers.contains_key(&invalid.hash()));
+
+        let other = SignedTransaction::from_raw(Transaction::default(), &key_pair::random());
+        assert_eq!(verifier.verify_one(&other), other.verified_signers());
+        assert!(verifier.cache.lock().unwrap().signers.contains_key(&other.hash()));
+    }
+}
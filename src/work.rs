@@ -0,0 +1,336 @@
+use crate::address::H160;
+use crate::api::client;
+use crate::block::{Block, Header};
+use crate::block_template::BlockTemplate;
+use crate::blockchain::{BlockOrigin, Blockchain};
+use crate::clock::Clock;
+use crate::crypto::hash::{H256, Hashable};
+use crate::mempool::Mempool;
+use crate::network::message::Message;
+use crate::network::server::Handle as ServerHandle;
+use crate::pool::Pool;
+
+use log::info;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many templates handed out to one worker are remembered, the oldest being forgotten first
+const MAX_WORK_PER_WORKER: usize = 16;
+/// How far ahead of this node's clock the timestamp of a submitted header may be, in milliseconds
+const MAX_TIMESTAMP_DRIFT_MS: u128 = 60_000;
+/// How often an external worker asks for fresh work
+const WORK_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A block template handed out to an external worker, which searches for a nonce (and may roll
+/// the timestamp) such that the hash of the header is at most `target`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Work {
+    pub id: u64,
+    /// The hex-encoded, bincode-serialized header
+    pub header: String,
+    pub target: H256,
+}
+
+/// What a solved header submitted by a worker turned out to be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Solution {
+    /// A share of a pool, not meeting the block target
+    Share,
+    /// A block, inserted and broadcast
+    Block(H256),
+}
+
+/// A template handed out to a worker, and the hashes of the shares submitted for it
+struct IssuedWork {
+    template: BlockTemplate,
+    target: H256,
+    shares: HashSet<H256>,
+}
+
+/// Hands out block templates to external hashing processes, and accepts the headers they solve.
+/// In pool mode, workers are named by their address, and submit shares.
+pub struct WorkManager {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    pool: Option<Pool>,
+    /// Who the coinbase pays outside of pool mode
+    payout_address: H160,
+    /// The source of template timestamps, and of the time submitted timestamps are checked against
+    clock: Arc<dyn Clock>,
+    /// The templates handed out to each worker, by work id
+    templates: HashMap<String, BTreeMap<u64, IssuedWork>>,
+    next_id: u64,
+}
+
+impl WorkManager {
+    pub fn new(
+        server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        pool: Option<Pool>,
+        payout_address: H160,
+        clock: &Arc<dyn Clock>,
+    ) -> Self {
+        WorkManager {
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            pool,
+            payout_address,
+            clock: Arc::clone(clock),
+            templates: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn pool(&self) -> Option<&Pool> {
+        self.pool.as_ref()
+    }
+
+    /// Assemble a template on the current tip for `worker`
+    pub fn get_work(&mut self, worker: &str) -> Result<Work, String> {
+        let blockchain = self.blockchain.lock().unwrap();
+        let mempool = self.mempool.lock().unwrap();
+        let (template, target) = match &self.pool {
+            Some(pool) => {
+                let address = parse_address(worker)?;
+                // before the first share, the worker asking is paid:
+                let mut payouts = pool.payouts();
+                if payouts.is_empty() {
+                    payouts.push((address, 1));
+                }
+                let template = BlockTemplate::assemble(&blockchain, &mempool, &payouts, self.clock.now_ms());
+                let target = pool.share_target(&template.header.difficulty);
+                (template, target)
+            }
+            None => {
+                let payouts = [(self.payout_address, 1)];
+                let template = BlockTemplate::assemble(&blockchain, &mempool, &payouts, self.clock.now_ms());
+                let target = template.header.difficulty;
+                (template, target)
+            }
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let work = Work {
+            id,
+            header: hex::encode(bincode::serialize(&template.header).unwrap()),
+            target,
+        };
+
+        // forget the work on older tips, of all workers, which can no longer be submitted:
+        let tip = blockchain.tip();
+        self.templates.retain(|_, templates| {
+            templates.retain(|_, issued| issued.template.header.parent == tip);
+            !templates.is_empty()
+        });
+        let templates = self.templates.entry(worker.to_string()).or_default();
+        templates.insert(id, IssuedWork { template, target, shares: HashSet::new() });
+        while templates.len() > MAX_WORK_PER_WORKER {
+            let oldest = *templates.keys().next().unwrap();
+            templates.remove(&oldest);
+        }
+        Ok(work)
+    }
+
+    /// Accept the header of work `id` of `worker` solved by the worker. A share is credited to
+    /// the worker, and a block is inserted and broadcast.
+    pub fn submit_work(&mut self, worker: &str, id: u64, header: Header) -> Result<Solution, String> {
+        let issued = self.templates.get_mut(worker).and_then(|templates| templates.get_mut(&id))
+            .ok_or("unknown work")?;
+        let template = &issued.template;
+        if header.parent != template.header.parent
+            || header.difficulty != template.header.difficulty
+            || header.merkle_root != template.header.merkle_root {
+            return Err("header does not match the work".to_string());
+        }
+        // the worker may roll the timestamp, but neither back nor far into the future:
+        let now = self.clock.now_ms();
+        if header.timestamp < template.header.timestamp || header.timestamp > now + MAX_TIMESTAMP_DRIFT_MS {
+            return Err("timestamp out of range".to_string());
+        }
+        let block = Block { header, content: template.content.clone() };
+        let hash = block.hash();
+        let mut blockchain = self.blockchain.lock().unwrap();
+        if block.header.parent != blockchain.tip() {
+            return Err("stale work".to_string());
+        }
+        let is_block = blockchain.pow_validity_check(&block);
+        if !is_block && hash > issued.target {
+            return Err("insufficient proof of work".to_string());
+        }
+        if !issued.shares.insert(hash) {
+            return Err("duplicate share".to_string());
+        }
+        if let Some(pool) = &mut self.pool {
+            pool.add_share(parse_address(worker)?);
+        }
+        if !is_block {
+            return Ok(Solution::Share);
+        }
+        let old_tip = blockchain.tip();
+        blockchain.insert(&block);
+        self.mempool.lock().unwrap().update_tip(&old_tip, &blockchain);
+        self.server.broadcast(Message::NewBlockHashes(vec![hash]));
+        blockchain.hash_to_origin.insert(hash, BlockOrigin::Mined);
+        self.templates.get_mut(worker).unwrap().remove(&id);
+        info!("Worker {} mined block {}", worker, hash);
+        Ok(Solution::Block(hash))
+    }
+}
+
+fn parse_address(worker: &str) -> Result<H160, String> {
+    let bytes = hex::decode(worker).map_err(|e| format!("worker is not an address: {}", e))?;
+    let array: [u8; 20] = bytes[..].try_into().map_err(|_| "worker is not an address".to_string())?;
+    Ok(array.into())
+}
+
+/// Parse a hex-encoded, bincode-serialized header
+pub fn parse_header(s: &str) -> Result<Header, String> {
+    let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+    bincode::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
+/// An external hashing process, getting work from and submitting solutions to a node's API
+pub struct Worker {
+    /// API address of the node
+    pub api: String,
+    /// The name the node tracks the work of this worker under, i.e. its address in pool mode
+    pub name: String,
+    /// The source of rolled timestamps
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Worker {
+    /// Search for blocks (or shares) forever, asking for fresh work every so often
+    pub fn run(&self) -> Result<(), String> {
+        loop {
+            let work: Work = client::get(&self.api, &format!("/mining/getwork?worker={}", self.name))?;
+            let mut header = parse_header(&work.header)?;
+            header.nonce = rand::random();
+            let started = Instant::now();
+            while started.elapsed() < WORK_REFRESH_INTERVAL {
+                header.nonce = header.nonce.wrapping_add(1);
+                if header.nonce == 0 {
+                    // the nonces are exhausted, so roll the timestamp:
+                    header.timestamp = self.clock.now_ms();
+                }
+                if header.hash() <= work.target {
+                    let path = format!("/mining/submitwork?worker={}&id={}&header={}",
+                        self.name, work.id, hex::encode(bincode::serialize(&header).unwrap()));
+                    match client::call(&self.api, &path) {
+                        // keep searching the same work for more shares:
+                        Ok(message) if message == "share" => {}
+                        Ok(hash) => {
+                            info!("Found block {}", hash);
+                            break;
+                        }
+                        Err(e) => {
+                            info!("Solution rejected: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BLOCK_REWARD;
+    use crate::chain_spec::ChainSpec;
+    use crate::clock::ManualClock;
+    use crate::network::server;
+    use crossbeam::channel::unbounded;
+
+    /// A work manager whose clock stands at `NOW_MS`
+    fn manager(spec: &ChainSpec, pool: Option<Pool>) -> (server::Context, WorkManager) {
+        let (msg_sender, _msg_receiver) = unbounded();
+        let (server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_sender).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_spec(spec)));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new(NOW_MS as u64));
+        (server_ctx, WorkManager::new(&server, &blockchain, &mempool, pool, H160::from([9u8; 20]), &clock))
+    }
+
+    const NOW_MS: u128 = 1_000_000;
+
+    #[test]
+    fn submit_work() {
+        // any hash is good enough on a regtest chain:
+        let (_server_ctx, mut manager) = manager(&ChainSpec { regtest: true, ..ChainSpec::default() }, None);
+        let first = manager.get_work("a").unwrap();
+        let second = manager.get_work("b").unwrap();
+        let header = parse_header(&first.header).unwrap();
+        assert_eq!(header.timestamp, NOW_MS);
+        assert!(manager.submit_work("b", first.id, header.clone()).is_err());
+        let mut tampered = header.clone();
+        tampered.merkle_root = H256::default();
+        assert_eq!(manager.submit_work("a", first.id, tampered), Err("header does not match the work".to_string()));
+
+        let hash = match manager.submit_work("a", first.id, header.clone()) {
+            Ok(Solution::Block(hash)) => hash,
+            result => panic!("unexpected {:?}", result),
+        };
+        assert_eq!(manager.blockchain.lock().unwrap().tip(), hash);
+        assert!(manager.submit_work("a", first.id, header).is_err());
+        let stale = parse_header(&second.header).unwrap();
+        assert_eq!(manager.submit_work("b", second.id, stale), Err("stale work".to_string()));
+        // the work on the old tip is forgotten once work on the new one is handed out:
+        let third = manager.get_work("a").unwrap();
+        assert_eq!(manager.templates.keys().collect::<Vec<_>>(), vec!["a"]);
+
+        let mut header = parse_header(&third.header).unwrap();
+        header.timestamp -= 1;
+        assert_eq!(manager.submit_work("a", third.id, header.clone()), Err("timestamp out of range".to_string()));
+        header.timestamp = NOW_MS + MAX_TIMESTAMP_DRIFT_MS + 1;
+        assert_eq!(manager.submit_work("a", third.id, header), Err("timestamp out of range".to_string()));
+
+        // outside of pool mode, the node's own address is paid:
+        let blockchain = manager.blockchain.lock().unwrap();
+        let coinbase = &blockchain.get_block(&hash).content.transactions[0];
+        assert_eq!(coinbase.raw.TransactionOutput[0].recipient, H160::from([9u8; 20]));
+        assert_eq!(coinbase.raw.TransactionOutput[0].value, BLOCK_REWARD);
+    }
+
+    #[test]
+    fn pool_shares() {
+        let (_server_ctx, mut manager) = manager(&ChainSpec::default(), Some(Pool::new(2, 10)));
+        let (a, b) = (H160::from([1u8; 20]), H160::from([2u8; 20]));
+        assert!(manager.get_work("not an address").is_err());
+
+        // find a share that is not a block:
+        let work = manager.get_work(&a.to_string()).unwrap();
+        let mut header = parse_header(&work.header).unwrap();
+        while header.hash() > work.target || header.hash() <= header.difficulty {
+            header.nonce += 1;
+        }
+        assert_eq!(manager.submit_work(&a.to_string(), work.id, header.clone()), Ok(Solution::Share));
+        assert_eq!(manager.submit_work(&a.to_string(), work.id, header), Err("duplicate share".to_string()));
+
+        // the next block pays the shares in the window, even if found by another worker:
+        let work = manager.get_work(&b.to_string()).unwrap();
+        let mut header = parse_header(&work.header).unwrap();
+        while header.hash() > header.difficulty {
+            header.nonce += 1;
+        }
+        let hash = match manager.submit_work(&b.to_string(), work.id, header) {
+            Ok(Solution::Block(hash)) => hash,
+            result => panic!("unexpected {:?}", result),
+        };
+        let blockchain = manager.blockchain.lock().unwrap();
+        let coinbase = &blockchain.get_block(&hash).content.transactions[0];
+        let paid: Vec<(H160, u64)> = coinbase.raw.TransactionOutput.iter()
+            .filter(|output| output.value > 0)
+            .map(|output| (output.recipient, output.value))
+            .collect();
+        assert_eq!(paid, vec![(a, BLOCK_REWARD)]);
+        assert_eq!(manager.pool().unwrap().payouts(), vec![(a, 1), (b, 1)]);
+    }
+}